@@ -0,0 +1,833 @@
+// https://gbdev.io/pandocs/Audio.html
+// https://gbdev.io/pandocs/Audio_Registers.html
+// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware
+use crate::gameboy::CPU_CLOCK_HZ;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// The frame sequencer runs at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK_HZ / 512;
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Undrained output kept before the oldest samples start being dropped
+const MAX_BUFFERED_SECONDS: usize = 1;
+
+// Bits that always read back as 1 for 0xFF10-0xFF2F (write-only and unused bits)
+const READ_MASKS: [u8; 32] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // (unused), NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // (unused), NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+// Length timer shared by all channels; counts up to `max` and then silences the channel
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+
+impl LengthCounter {
+    fn load(&mut self, max: u16, value: u16) {
+        self.counter = max - value;
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    // Returns true when the channel should be switched off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.bool()?;
+        self.counter = r.u16()?;
+        Ok(())
+    }
+}
+
+// Volume envelope used by the pulse and noise channels (NRx2)
+#[derive(Default)]
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn dac_enabled(&self) -> bool {
+        (self.register & 0xF8) != 0
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.register & 0x07;
+    }
+
+    fn clock(&mut self) {
+        let pace = self.register & 0x07;
+        if pace == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = pace;
+            let increase = (self.register & 0x08) != 0;
+            if increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.register = r.u8()?;
+        self.volume = r.u8()?;
+        self.timer = r.u8()?;
+        Ok(())
+    }
+}
+
+// Channels 1 and 2. Only channel 1 has a frequency sweep.
+#[derive(Default)]
+struct PulseChannel {
+    enabled: bool,
+    has_sweep: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    // NR10 and sweep unit state
+    sweep_register: u8,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+}
+
+impl PulseChannel {
+    fn new(has_sweep: bool) -> Self {
+        PulseChannel {
+            has_sweep,
+            ..Default::default()
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some(DUTY_TABLE[self.duty as usize][self.duty_position as usize] * self.envelope.volume)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            let pace = (self.sweep_register >> 4) & 0x07;
+            let step = self.sweep_register & 0x07;
+            self.shadow_frequency = self.frequency;
+            self.sweep_timer = if pace == 0 { 8 } else { pace };
+            self.sweep_enabled = pace != 0 || step != 0;
+            if step != 0 {
+                self.sweep_calculate();
+            }
+        }
+    }
+
+    // Computes the next sweep frequency, disabling the channel on overflow
+    fn sweep_calculate(&mut self) -> u16 {
+        let step = self.sweep_register & 0x07;
+        let delta = self.shadow_frequency >> step;
+        let decrease = (self.sweep_register & 0x08) != 0;
+        let new_frequency = if decrease {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        if new_frequency > 2047 {
+            self.enabled = false;
+        }
+        new_frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        let pace = (self.sweep_register >> 4) & 0x07;
+        self.sweep_timer = if pace == 0 { 8 } else { pace };
+        if !self.sweep_enabled || pace == 0 {
+            return;
+        }
+
+        let new_frequency = self.sweep_calculate();
+        if new_frequency <= 2047 && (self.sweep_register & 0x07) != 0 {
+            self.frequency = new_frequency;
+            self.shadow_frequency = new_frequency;
+            // A second overflow check runs with the new frequency
+            self.sweep_calculate();
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.duty);
+        w.u8(self.duty_position);
+        w.u16(self.frequency);
+        w.u32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u8(self.sweep_register);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_timer);
+        w.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.bool()?;
+        self.duty = r.u8()? & 0x03;
+        self.duty_position = r.u8()? & 0x07;
+        self.frequency = r.u16()? & 0x7FF;
+        self.timer = r.u32()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.sweep_register = r.u8()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_timer = r.u8()?;
+        self.shadow_frequency = r.u16()?;
+        Ok(())
+    }
+}
+
+// Channel 3: plays back 32 4-bit samples from wave RAM
+#[derive(Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    output_level: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample_buffer: u8,
+    length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            // High nibble plays first
+            self.sample_buffer = if self.position.is_multiple_of(2) {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        let sample = match self.output_level {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            _ => self.sample_buffer >> 2,
+        };
+        Some(sample)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(256);
+        // The first sample is delayed slightly on hardware
+        self.timer = self.period() + 6;
+        self.position = 0;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.output_level);
+        w.u16(self.frequency);
+        w.u32(self.timer);
+        w.u8(self.position);
+        w.u8(self.sample_buffer);
+        self.length.save_state(w);
+        w.bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.output_level = r.u8()? & 0x03;
+        self.frequency = r.u16()? & 0x7FF;
+        self.timer = r.u32()?;
+        self.position = r.u8()? & 0x1F;
+        self.sample_buffer = r.u8()?;
+        self.length.load_state(r)?;
+        r.bytes_into(&mut self.wave_ram)
+    }
+}
+
+// Channel 4: pseudo-random noise from a 15-bit LFSR
+#[derive(Default)]
+struct NoiseChannel {
+    enabled: bool,
+    polynomial: u8, // NR43
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn period(&self) -> u32 {
+        let divisor = NOISE_DIVISORS[(self.polynomial & 0x07) as usize];
+        divisor << (self.polynomial >> 4)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            // 7-bit mode also feeds back into bit 6
+            if (self.polynomial & 0x08) != 0 {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        let bit = (!self.lfsr & 0x01) as u8;
+        Some(bit * self.envelope.volume)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.polynomial);
+        w.u32(self.timer);
+        w.u16(self.lfsr);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.bool()?;
+        self.polynomial = r.u8()?;
+        self.timer = r.u32()?;
+        self.lfsr = r.u16()? & 0x7FFF;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)
+    }
+}
+
+pub struct Apu {
+    ch1: PulseChannel,
+    ch2: PulseChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+
+    powered: bool,
+    nr50: u8, // Master volume / VIN panning
+    nr51: u8, // Sound panning
+
+    frame_sequencer_timer: u32,
+    frame_sequencer_step: u8,
+
+    // Output resampling. A sample rate of 0 means nobody is listening and no
+    // samples are produced.
+    sample_rate: u32,
+    sample_counter: u64,
+    samples: Vec<f32>,
+    // DC-blocking high-pass filter state per side, like the output capacitor on hardware
+    capacitor: [f32; 2],
+    capacitor_factor: f32,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            ch1: PulseChannel::new(true),
+            ch2: PulseChannel::new(false),
+            ch3: WaveChannel::default(),
+            ch4: NoiseChannel::default(),
+            powered: true,
+            nr50: 0x77,
+            nr51: 0xF3,
+            frame_sequencer_timer: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+            sample_rate: 0,
+            sample_counter: 0,
+            samples: Vec::new(),
+            capacitor: [0.0; 2],
+            capacitor_factor: 0.0,
+        }
+    }
+
+    // Starts producing interleaved stereo samples at `rate` Hz
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.sample_counter = 0;
+        self.samples.clear();
+        if rate > 0 {
+            self.capacitor_factor = 0.999958_f32.powf(CPU_CLOCK_HZ as f32 / rate as f32);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Hands over the samples produced since the last call (left, right, left, ...).
+    // Only the last second or so is kept if nobody calls this.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        let cycles = cycles as u32;
+
+        if self.powered {
+            self.ch1.tick(cycles);
+            self.ch2.tick(cycles);
+            self.ch3.tick(cycles);
+            self.ch4.tick(cycles);
+
+            if self.frame_sequencer_timer > cycles {
+                self.frame_sequencer_timer -= cycles;
+            } else {
+                self.frame_sequencer_timer += FRAME_SEQUENCER_PERIOD - cycles;
+                self.clock_frame_sequencer();
+            }
+        }
+
+        if self.sample_rate > 0 {
+            // Emit one output sample every CPU_CLOCK_HZ / sample_rate cycles
+            self.sample_counter += cycles as u64 * self.sample_rate as u64;
+            while self.sample_counter >= CPU_CLOCK_HZ as u64 {
+                self.sample_counter -= CPU_CLOCK_HZ as u64;
+                self.push_sample();
+            }
+        }
+    }
+
+    // Length at steps 0/2/4/6, sweep at 2/6, envelope at 7
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) & 0x07;
+    }
+
+    fn push_sample(&mut self) {
+        let outputs = [
+            self.ch1.output(),
+            self.ch2.output(),
+            self.ch3.output(),
+            self.ch4.output(),
+        ];
+
+        let mut mixed = [0.0_f32; 2];
+        if self.powered {
+            for (i, output) in outputs.iter().enumerate() {
+                // A DAC maps 0..15 to roughly -1..1; a disabled DAC outputs nothing
+                let Some(value) = output else {
+                    continue;
+                };
+                let analog = 1.0 - (*value as f32 / 7.5);
+                if (self.nr51 & (0x10 << i)) != 0 {
+                    mixed[0] += analog;
+                }
+                if (self.nr51 & (0x01 << i)) != 0 {
+                    mixed[1] += analog;
+                }
+            }
+            let left_volume = ((self.nr50 >> 4) & 0x07) as f32 + 1.0;
+            let right_volume = (self.nr50 & 0x07) as f32 + 1.0;
+            mixed[0] *= left_volume / 8.0 / 4.0;
+            mixed[1] *= right_volume / 8.0 / 4.0;
+        }
+
+        // Nobody is draining the buffer: drop the older half rather than grow
+        // without bound
+        let limit = self.sample_rate as usize * 2 * MAX_BUFFERED_SECONDS;
+        if self.samples.len() >= limit {
+            self.samples.drain(..limit / 2);
+        }
+        for (side, sample) in mixed.iter().enumerate() {
+            let filtered = sample - self.capacitor[side];
+            self.capacitor[side] = sample - filtered * self.capacitor_factor;
+            self.samples.push(filtered);
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.read_register(address) | READ_MASKS[index]
+            }
+            0xFF26 => {
+                let mut value = READ_MASKS[0x16];
+                if self.powered {
+                    value |= 0x80;
+                }
+                for (bit, on) in [
+                    self.ch1.enabled,
+                    self.ch2.enabled,
+                    self.ch3.enabled,
+                    self.ch4.enabled,
+                ]
+                .iter()
+                .enumerate()
+                {
+                    if *on {
+                        value |= 1 << bit;
+                    }
+                }
+                value
+            }
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => self.ch3.wave_ram[(address - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF10 => self.ch1.sweep_register,
+            0xFF11 => self.ch1.duty << 6,
+            0xFF12 => self.ch1.envelope.register,
+            0xFF14 => (self.ch1.length.enabled as u8) << 6,
+            0xFF16 => self.ch2.duty << 6,
+            0xFF17 => self.ch2.envelope.register,
+            0xFF19 => (self.ch2.length.enabled as u8) << 6,
+            0xFF1A => (self.ch3.dac_enabled as u8) << 7,
+            0xFF1C => self.ch3.output_level << 5,
+            0xFF1E => (self.ch3.length.enabled as u8) << 6,
+            0xFF21 => self.ch4.envelope.register,
+            0xFF22 => self.ch4.polynomial,
+            0xFF23 => (self.ch4.length.enabled as u8) << 6,
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            _ => 0x00,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if address == 0xFF26 {
+            self.write_power(value);
+            return;
+        }
+        if let 0xFF30..=0xFF3F = address {
+            self.ch3.wave_ram[(address - 0xFF30) as usize] = value;
+            return;
+        }
+
+        // While powered off only the length timers can be written (DMG behaviour)
+        if !self.powered {
+            match address {
+                0xFF11 => self.ch1.length.load(64, (value & 0x3F) as u16),
+                0xFF16 => self.ch2.length.load(64, (value & 0x3F) as u16),
+                0xFF1B => self.ch3.length.load(256, value as u16),
+                0xFF20 => self.ch4.length.load(64, (value & 0x3F) as u16),
+                _ => {}
+            }
+            return;
+        }
+
+        match address {
+            // Channel 1
+            0xFF10 => self.ch1.sweep_register = value & 0x7F,
+            0xFF11 => {
+                self.ch1.duty = value >> 6;
+                self.ch1.length.load(64, (value & 0x3F) as u16);
+            }
+            0xFF12 => {
+                self.ch1.envelope.register = value;
+                if !self.ch1.envelope.dac_enabled() {
+                    self.ch1.enabled = false;
+                }
+            }
+            0xFF13 => self.ch1.frequency = (self.ch1.frequency & 0x700) | value as u16,
+            0xFF14 => {
+                self.ch1.frequency = (self.ch1.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.ch1.length.enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.ch1.trigger();
+                }
+            }
+
+            // Channel 2
+            0xFF16 => {
+                self.ch2.duty = value >> 6;
+                self.ch2.length.load(64, (value & 0x3F) as u16);
+            }
+            0xFF17 => {
+                self.ch2.envelope.register = value;
+                if !self.ch2.envelope.dac_enabled() {
+                    self.ch2.enabled = false;
+                }
+            }
+            0xFF18 => self.ch2.frequency = (self.ch2.frequency & 0x700) | value as u16,
+            0xFF19 => {
+                self.ch2.frequency = (self.ch2.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.ch2.length.enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.ch2.trigger();
+                }
+            }
+
+            // Channel 3
+            0xFF1A => {
+                self.ch3.dac_enabled = (value & 0x80) != 0;
+                if !self.ch3.dac_enabled {
+                    self.ch3.enabled = false;
+                }
+            }
+            0xFF1B => self.ch3.length.load(256, value as u16),
+            0xFF1C => self.ch3.output_level = (value >> 5) & 0x03,
+            0xFF1D => self.ch3.frequency = (self.ch3.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.ch3.frequency = (self.ch3.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.ch3.length.enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.ch3.trigger();
+                }
+            }
+
+            // Channel 4
+            0xFF20 => self.ch4.length.load(64, (value & 0x3F) as u16),
+            0xFF21 => {
+                self.ch4.envelope.register = value;
+                if !self.ch4.envelope.dac_enabled() {
+                    self.ch4.enabled = false;
+                }
+            }
+            0xFF22 => self.ch4.polynomial = value,
+            0xFF23 => {
+                self.ch4.length.enabled = (value & 0x40) != 0;
+                if (value & 0x80) != 0 {
+                    self.ch4.trigger();
+                }
+            }
+
+            // Control
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, value: u8) {
+        let power_on = (value & 0x80) != 0;
+        if self.powered && !power_on {
+            // Powering off clears every register except wave RAM and, on DMG,
+            // the length timers
+            let wave_ram = self.ch3.wave_ram;
+            let lengths = [
+                self.ch1.length.counter,
+                self.ch2.length.counter,
+                self.ch3.length.counter,
+                self.ch4.length.counter,
+            ];
+            self.ch1 = PulseChannel::new(true);
+            self.ch2 = PulseChannel::new(false);
+            self.ch3 = WaveChannel {
+                wave_ram,
+                ..Default::default()
+            };
+            self.ch4 = NoiseChannel::default();
+            self.ch1.length.counter = lengths[0];
+            self.ch2.length.counter = lengths[1];
+            self.ch3.length.counter = lengths[2];
+            self.ch4.length.counter = lengths[3];
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.powered && power_on {
+            self.frame_sequencer_step = 0;
+            self.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+        }
+        self.powered = power_on;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.ch1.save_state(w);
+        self.ch2.save_state(w);
+        self.ch3.save_state(w);
+        self.ch4.save_state(w);
+        w.bool(self.powered);
+        w.u8(self.nr50);
+        w.u8(self.nr51);
+        w.u32(self.frame_sequencer_timer);
+        w.u8(self.frame_sequencer_step);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ch1.load_state(r)?;
+        self.ch2.load_state(r)?;
+        self.ch3.load_state(r)?;
+        self.ch4.load_state(r)?;
+        self.powered = r.bool()?;
+        self.nr50 = r.u8()?;
+        self.nr51 = r.u8()?;
+        self.frame_sequencer_timer = r.u32()?;
+        self.frame_sequencer_step = r.u8()? & 0x07;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undrained_samples_are_capped() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(8000);
+        for _ in 0..3 * CPU_CLOCK_HZ / 4 {
+            apu.tick(4);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.len() <= 8000 * 2 * MAX_BUFFERED_SECONDS);
+        assert!(samples.len() >= 8000 * MAX_BUFFERED_SECONDS);
+        assert_eq!(samples.len() % 2, 0);
+    }
+}
@@ -0,0 +1,74 @@
+// Transparent loading of ROMs kept in .zip archives or .gz streams.
+// Archives are detected by their magic bytes, not the file extension.
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::cartridge::{CartridgeError, MAX_ROM_SIZE};
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// Returns the raw ROM image, unpacking it first if `data` is an archive
+pub fn extract_rom(data: Vec<u8>) -> Result<Vec<u8>, CartridgeError> {
+    if data.starts_with(&ZIP_MAGIC) {
+        extract_zip(data)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        read_limited(GzDecoder::new(data.as_slice()))
+    } else {
+        Ok(data)
+    }
+}
+
+// Picks the first .gb/.gbc entry, in archive order
+fn extract_zip(data: Vec<u8>) -> Result<Vec<u8>, CartridgeError> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(std::io::Error::from)?;
+
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).map_err(std::io::Error::from)?;
+        let name = entry.name().to_ascii_lowercase();
+        if entry.is_file() && (name.ends_with(".gb") || name.ends_with(".gbc")) {
+            return read_limited(entry);
+        }
+    }
+
+    Err(CartridgeError::NoRomInArchive)
+}
+
+// Decompresses at most one byte past the largest possible ROM, so neither a
+// forged size in a zip header nor a decompression bomb can exhaust memory
+fn read_limited(reader: impl Read) -> Result<Vec<u8>, CartridgeError> {
+    let mut rom = Vec::new();
+    reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(CartridgeError::TooLarge);
+    }
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let rom = vec![0x42; MAX_ROM_SIZE];
+        assert_eq!(extract_rom(gzip(&rom)).unwrap(), rom);
+    }
+
+    #[test]
+    fn gzip_bomb_is_refused() {
+        let bomb = gzip(&vec![0; MAX_ROM_SIZE + 1]);
+        assert!(matches!(extract_rom(bomb), Err(CartridgeError::TooLarge)));
+    }
+}
@@ -0,0 +1,96 @@
+// Feeds the APU's samples to the host's default output device
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// Keep at most ~100ms queued so audio can't drift far behind the picture
+const MAX_LATENCY_MS: usize = 100;
+
+pub struct AudioOutput {
+    // Dropping the stream stops playback
+    _stream: Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+    max_queued: usize,
+}
+
+impl AudioOutput {
+    pub fn new() -> Result<Self, String> {
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or("no audio output device")?;
+        let supported = device.default_output_config().map_err(|e| e.to_string())?;
+        let format = supported.sample_format();
+        let config: StreamConfig = supported.into();
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let stream = match format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            other => return Err(format!("unsupported sample format {}", other)),
+        }?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        let sample_rate = config.sample_rate.0;
+        Ok(AudioOutput {
+            _stream: stream,
+            queue,
+            sample_rate,
+            max_queued: sample_rate as usize * 2 * MAX_LATENCY_MS / 1000,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Queues interleaved stereo samples, dropping the oldest ones if we're too far ahead
+    pub fn push(&self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+        while queue.len() > self.max_queued {
+            queue.pop_front();
+            queue.pop_front();
+        }
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+) -> Result<Stream, String>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut queue = queue.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    // Underruns play silence rather than stalling the emulator
+                    let left = queue.pop_front().unwrap_or(0.0);
+                    let right = queue.pop_front().unwrap_or(0.0);
+                    if channels == 1 {
+                        frame[0] = T::from_sample((left + right) / 2.0);
+                        continue;
+                    }
+                    for (i, sample) in frame.iter_mut().enumerate() {
+                        *sample = T::from_sample(match i {
+                            0 => left,
+                            1 => right,
+                            _ => 0.0,
+                        });
+                    }
+                }
+            },
+            |e| eprintln!("Audio stream error: {}", e),
+            None,
+        )
+        .map_err(|e| e.to_string())
+}
@@ -1,13 +1,19 @@
+use crate::apu::Apu;
 use crate::cartridge::Cartridge;
 use crate::dma::Dma;
 use crate::interrupts::Interrupt;
 use crate::joypad::Joypad;
+use crate::memory_bus::MemoryBus;
 use crate::ppu::Ppu;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::serial::Serial;
 use crate::timer::Timer;
 pub struct Bus {
     pub joypad: Joypad,
     pub ppu: Ppu,
     pub timer: Timer,
+    pub apu: Apu,
+    pub serial: Serial,
     pub dma: Dma,
     cartridge: Cartridge,
     pub ie_reg: u8,
@@ -22,6 +28,8 @@ impl Bus {
             joypad: Joypad::new(),
             ppu: Ppu::new(),
             timer: Timer::new(),
+            apu: Apu::new(),
+            serial: Serial::new(),
             dma: Dma::new(),
             cartridge,
             ie_reg: 0,
@@ -36,6 +44,17 @@ impl Bus {
             self.request_interrupt(Interrupt::Timer);
         }
 
+        if self.serial.tick(cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+
+        self.apu.tick(cycles);
+        for i in self.dma.tick(cycles) {
+            let source = ((self.dma.byte as u16) << 8) | i;
+            self.ppu.oam[i as usize] = self.dma_read(source);
+        }
+        self.cartridge.tick(cycles);
+
         let (vblank, stat) = self.ppu.tick(cycles);
         if vblank {
             self.request_interrupt(Interrupt::VBlank);
@@ -45,6 +64,46 @@ impl Bus {
         }
     }
 
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    pub fn save_ram(&mut self) -> std::io::Result<()> {
+        self.cartridge.save_ram()
+    }
+
+    pub fn rumble_active(&self) -> bool {
+        self.cartridge.rumble_active()
+    }
+
+    pub fn save_state(&mut self, w: &mut StateWriter) {
+        w.u8(self.ie_reg);
+        w.u8(self.int_flag);
+        w.bytes(&self.wram);
+        w.bytes(&self.hram);
+        self.ppu.save_state(w);
+        self.timer.save_state(w);
+        self.apu.save_state(w);
+        self.serial.save_state(w);
+        self.dma.save_state(w);
+        self.joypad.save_state(w);
+        self.cartridge.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ie_reg = r.u8()?;
+        self.int_flag = r.u8()?;
+        r.bytes_into(&mut self.wram)?;
+        r.bytes_into(&mut self.hram)?;
+        self.ppu.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.dma.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.cartridge.load_state(r)
+    }
+
     pub fn request_interrupt(&mut self, interrupt: Interrupt) {
         self.int_flag |= interrupt.mask();
     }
@@ -59,12 +118,15 @@ impl Bus {
             0xC000..=0xDFFF => self.wram[(address - 0xC000) as usize],
             0xE000..=0xFDFF => self.wram[(address - 0xE000) as usize],
 
+            0xFE00..=0xFE9F if self.oam_dma_locked() => 0xFF,
             0xFE00..=0xFE9F => self.ppu.read(address),
 
             0xFF00..=0xFF7F => match address {
                 0xFF00 => self.joypad.read(),
                 0xFF0F => self.int_flag,
+                0xFF01..=0xFF02 => self.serial.read(address),
                 0xFF04..=0xFF07 => self.timer.read(address),
+                0xFF10..=0xFF3F => self.apu.read(address),
 
                 0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.read(address),
 
@@ -86,17 +148,17 @@ impl Bus {
             0xA000..=0xBFFF => self.cartridge.write(address, byte),
             0xC000..=0xDFFF => self.wram[(address - 0xC000) as usize] = byte, // WRAM Logic
 
+            0xFE00..=0xFE9F if self.oam_dma_locked() => {}
             0xFE00..=0xFE9F => self.ppu.write(address, byte),
 
             0xFF00..=0xFF7F => match address {
                 0xFF00 => self.joypad.write(byte),
                 0xFF0F => self.int_flag = byte,
+                0xFF01..=0xFF02 => self.serial.write(address, byte),
                 0xFF04..=0xFF07 => self.timer.write(address, byte),
+                0xFF10..=0xFF3F => self.apu.write(address, byte),
 
-                0xFF46 => {
-                    self.dma.start(byte);
-                    self.dma_transfer(byte);
-                }
+                0xFF46 => self.dma.start(byte),
 
                 0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.write(address, byte),
                 _ => {}
@@ -107,11 +169,50 @@ impl Bus {
         }
     }
 
-    fn dma_transfer(&mut self, value: u8) {
-        let base = (value as u16) << 8;
-        for i in 0..160 {
-            let byte = self.read_byte(base + i);
-            self.ppu.oam[i as usize] = byte;
+    // OAM belongs to the DMA unit while a transfer is running
+    fn oam_dma_locked(&self) -> bool {
+        self.ppu.access_blocking && self.dma.is_transferring()
+    }
+
+    // DMA has its own path to memory, so the PPU's VRAM/OAM locks don't apply.
+    // Sources from 0xE000 up all land in (echo) WRAM.
+    fn dma_read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.read(address),
+            0x8000..=0x9FFF => self.ppu.vram[(address - 0x8000) as usize],
+            _ => self.wram[(address & 0x1FFF) as usize],
         }
     }
 }
+
+impl MemoryBus for Bus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.read_byte(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.write_byte(address, value);
+    }
+
+    fn tick(&mut self) {
+        Bus::tick(self, 4);
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.int_flag & self.ie_reg & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+        self.int_flag &= !interrupt.mask();
+    }
+
+    fn stop(&mut self) {
+        self.timer.write(0xFF04, 0);
+        // The DMG shows a blank (white) screen while stopped
+        self.ppu.buffer.fill(0xFFFFFFFF);
+    }
+
+    fn joypad_input(&self) -> bool {
+        (self.joypad.read() & 0x0F) != 0x0F
+    }
+}
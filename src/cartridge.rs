@@ -1,6 +1,18 @@
 // More Info can be found at https://gbdev.io/pandocs/The_Cartridge_Header.html
 
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::archive::extract_rom;
+use crate::licensee::{new_licensee_name, old_licensee_name, USE_NEW_LICENSEE};
+use crate::patch::{self, PatchError, PATCH_EXTENSIONS};
+use crate::rtc::{Rtc, RTC_SAVE_SIZE, RTC_SAVE_SIZE_LEGACY};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// The largest ROM any Game Boy mapper can address (MBC5's 512 banks)
+pub const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
 
 #[derive(Debug, Clone, Copy)]
 pub enum MbcType {
@@ -8,38 +20,171 @@ pub enum MbcType {
     MBC1,
     MBC2,
     MBC3,
+    MBC5,
     Unknown(u8),
 }
 
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // Smaller than the 0x150 bytes needed to hold a header
+    TooSmall(usize),
+    UnsupportedMapper(u8),
+    // The file is shorter than the ROM size declared in the header
+    SizeMismatch { header: usize, actual: usize },
+    BadChecksum { expected: u8, actual: u8 },
+    // A .zip archive without any .gb/.gbc entry
+    NoRomInArchive,
+    // An archive that unpacks to more than MAX_ROM_SIZE
+    TooLarge,
+    Patch(PatchError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "I/O error: {}", e),
+            CartridgeError::TooSmall(size) => {
+                write!(f, "ROM is too small ({} bytes, need at least 336)", size)
+            }
+            CartridgeError::UnsupportedMapper(code) => {
+                write!(f, "unsupported cartridge type {:#04X}", code)
+            }
+            CartridgeError::SizeMismatch { header, actual } => write!(
+                f,
+                "header declares {} KiB of ROM but the file is only {} bytes",
+                header / 1024,
+                actual
+            ),
+            CartridgeError::BadChecksum { expected, actual } => write!(
+                f,
+                "header checksum mismatch (header says {:#04X}, computed {:#04X})",
+                expected, actual
+            ),
+            CartridgeError::NoRomInArchive => write!(f, "archive does not contain a .gb/.gbc ROM"),
+            CartridgeError::TooLarge => write!(f, "archive unpacks to more than 8 MiB"),
+            CartridgeError::Patch(e) => write!(f, "failed to apply patch: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) => Some(e),
+            CartridgeError::Patch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
+
+// 0x143: whether the game uses Game Boy Color features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    DmgOnly,
+    Enhanced, // 0x80: runs on DMG too
+    CgbOnly,  // 0xC0
+}
+
+// 0x14A
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
 #[derive(Debug)]
 pub struct CartridgeHeader {
     pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub old_licensee_code: u8,
+    pub new_licensee_code: String,
+    pub destination: Destination,
+    pub version: u8,
+    pub cart_type_code: u8,
     pub cart_type: MbcType,
+    pub has_battery: bool,
+    pub has_rtc: bool,
+    pub has_rumble: bool,
     pub ram_size: usize,
     pub rom_size: usize,
     pub check_sum: u8,
+    pub global_check_sum: u16,
 }
 
 impl CartridgeHeader {
-    pub fn parse(contents: &[u8]) -> Result<Self, String> {
+    pub fn parse(contents: &[u8]) -> Result<Self, CartridgeError> {
         if contents.len() < 0x150 {
-            return Err("ROM is too small".to_string());
+            return Err(CartridgeError::TooSmall(contents.len()));
         }
 
-        let title_bytes = &contents[0x134..0x143];
+        let cgb_support = match contents[0x143] {
+            0xC0 => CgbSupport::CgbOnly,
+            0x80 => CgbSupport::Enhanced,
+            _ => CgbSupport::DmgOnly,
+        };
+
+        // Newer cartridges carve a 4 character manufacturer code out of the end of the title
+        let code_bytes = &contents[0x13F..0x143];
+        let manufacturer_code = if cgb_support != CgbSupport::DmgOnly
+            && code_bytes
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            Some(String::from_utf8_lossy(code_bytes).to_string())
+        } else {
+            None
+        };
+
+        let title_end = if manufacturer_code.is_some() {
+            0x13F
+        } else {
+            0x143
+        };
+        let title_bytes = &contents[0x134..title_end];
         let title = String::from_utf8_lossy(title_bytes)
             .trim_matches('\0')
             .to_string();
 
+        let old_licensee_code = contents[0x14B];
+        let new_licensee_code = String::from_utf8_lossy(&contents[0x144..0x146]).to_string();
+
+        // SGB functions are only enabled when the old licensee code also says 0x33
+        let sgb_support = contents[0x146] == 0x03 && old_licensee_code == USE_NEW_LICENSEE;
+
+        let destination = if contents[0x14A] == 0x00 {
+            Destination::Japan
+        } else {
+            Destination::Overseas
+        };
+
         let cart_type_bytes = contents[0x147];
         let cart_type = match cart_type_bytes {
             0x00 => MbcType::RomOnly,
             0x01..=0x03 => MbcType::MBC1,
             0x05..=0x06 => MbcType::MBC2,
             0x0F..=0x13 => MbcType::MBC3,
+            0x19..=0x1E => MbcType::MBC5,
             _ => MbcType::Unknown(cart_type_bytes),
         };
 
+        // Only these cartridge types keep their external RAM alive with a battery
+        let has_battery = matches!(
+            cart_type_bytes,
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        );
+
+        let has_rtc = matches!(cart_type_bytes, 0x0F | 0x10);
+        let has_rumble = matches!(cart_type_bytes, 0x1C..=0x1E);
+
         let rom_size = match contents[0x148] {
             0x00..=0x08 => 32 * 1024 * (1 << contents[0x148]),
             0x52 => 1_152 * 1024,
@@ -59,15 +204,66 @@ impl CartridgeHeader {
         };
 
         let checksum = contents[0x14D];
+        let global_checksum = u16::from_be_bytes([contents[0x14E], contents[0x14F]]);
 
         Ok(Self {
             title,
+            manufacturer_code,
+            cgb_support,
+            sgb_support,
+            old_licensee_code,
+            new_licensee_code,
+            destination,
+            version: contents[0x14C],
+            cart_type_code: cart_type_bytes,
             cart_type,
+            has_battery,
+            has_rtc,
+            has_rumble,
             ram_size,
             rom_size,
             check_sum: checksum,
+            global_check_sum: global_checksum,
         })
     }
+
+    pub fn licensee(&self) -> &'static str {
+        if self.old_licensee_code == USE_NEW_LICENSEE {
+            new_licensee_name(&self.new_licensee_code)
+        } else {
+            old_licensee_name(self.old_licensee_code)
+        }
+    }
+}
+
+const MBC2_RAM_SIZE: usize = 512;
+
+// Bitmap the boot ROM compares against 0x0104-0x0133
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// A .sav file that didn't match the cartridge's RAM size (other emulators
+// sometimes pad or truncate them). As much of it as overlaps was loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveSizeMismatch {
+    pub path: PathBuf,
+    pub actual: usize,
+    pub expected: usize,
+}
+
+impl fmt::Display for SaveSizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "save file {} is {} bytes, expected {}; loaded what fits",
+            self.path.display(),
+            self.actual,
+            self.expected
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -75,46 +271,226 @@ pub struct Cartridge {
     pub header: CartridgeHeader,
     pub rom_data: Vec<u8>,
     pub ram_data: Vec<u8>,
+    // The IPS/UPS/BPS patch applied to `rom_data` when it was loaded, if any
+    pub patch_path: Option<PathBuf>,
+    // Set when the .sav file had to be padded or truncated to fit
+    pub save_size_mismatch: Option<SaveSizeMismatch>,
+
+    // Battery-backed saves: None when the cartridge has no battery
+    save_path: Option<PathBuf>,
+    ram_dirty: bool,
 
-    // MBC1 State
+    // MBC1 State (also reused by MBC2, MBC3 and MBC5)
     ram_enabled: bool,
-    rom_bank: u8,
-    ram_bank: u8,
+    rom_bank: u16,    // MBC5 uses all 9 bits
+    ram_bank: u8,     // MBC3: 0x00-0x03 select RAM, 0x08-0x0C select an RTC register
     banking_mode: u8, // 0 = ROM Banking Mode, 1 = RAM Banking Mode
+    multicart: bool,  // MBC1M: only 4 bits of the ROM bank register are wired
+
+    // MBC3 real-time clock
+    rtc: Option<Rtc>,
+
+    // MBC5 rumble motor, driven by bit 3 of the RAM bank register
+    rumble: bool,
 }
 impl Cartridge {
-    pub fn new(file_path: &str) -> Result<Self, String> {
-        let rom_data = fs::read(file_path).map_err(|e| format!("Failed to read file: {}", e))?;
+    // Loads a ROM (optionally inside a .zip or .gz) from disk. Battery-backed
+    // RAM is persisted to a .sav file next to it. A .bps/.ups/.ips file with the
+    // same name as the ROM is applied automatically.
+    pub fn new(file_path: &str) -> Result<Self, CartridgeError> {
+        Self::with_patch(file_path, None)
+    }
+
+    // Like `new`, but applies `patch_path` instead of looking for a patch next
+    // to the ROM. The file on disk is never modified.
+    pub fn with_patch(file_path: &str, patch_path: Option<&str>) -> Result<Self, CartridgeError> {
+        let (rom_data, patch_path) = Self::read_image(file_path, patch_path)?;
+        let mut cartridge = Self::load(
+            rom_data,
+            Some(strip_gzip_extension(Path::new(file_path)).with_extension("sav")),
+        )?;
+        cartridge.patch_path = patch_path;
+        Ok(cartridge)
+    }
+
+    // The ROM image `with_patch` would run (unpacked and patched), without any
+    // of the loader's checks, for tools that inspect bad or unsupported dumps.
+    // Also returns the patch that was applied.
+    pub fn read_image(
+        file_path: &str,
+        patch_path: Option<&str>,
+    ) -> Result<(Vec<u8>, Option<PathBuf>), CartridgeError> {
+        let rom_path = Path::new(file_path);
+        let mut rom_data = extract_rom(fs::read(rom_path)?)?;
+
+        let patch_path = match patch_path {
+            Some(path) => Some(PathBuf::from(path)),
+            None => find_patch(&strip_gzip_extension(rom_path)),
+        };
+        if let Some(path) = &patch_path {
+            let patch_data = fs::read(path)?;
+            rom_data = patch::apply(&rom_data, &patch_data).map_err(CartridgeError::Patch)?;
+        }
+        Ok((rom_data, patch_path))
+    }
+
+    // Builds a cartridge from a ROM image already in memory. Nothing is read
+    // from or written to disk, so save RAM only lives as long as the cartridge.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, CartridgeError> {
+        Self::load(extract_rom(data)?, None)
+    }
+
+    fn load(mut rom_data: Vec<u8>, save_path: Option<PathBuf>) -> Result<Self, CartridgeError> {
         let header = CartridgeHeader::parse(&rom_data)?;
-        let ram_size = header.ram_size;
+
+        if let MbcType::Unknown(code) = header.cart_type {
+            return Err(CartridgeError::UnsupportedMapper(code));
+        }
+        if rom_data.len() < header.rom_size {
+            return Err(CartridgeError::SizeMismatch {
+                header: header.rom_size,
+                actual: rom_data.len(),
+            });
+        }
+        pad_rom(&mut rom_data);
+        let ram_size = match header.cart_type {
+            // MBC2 has 512 half-bytes of RAM built in; the header reports 0
+            MbcType::MBC2 => MBC2_RAM_SIZE,
+            _ => header.ram_size,
+        };
+
+        let mut rtc = if header.has_rtc {
+            Some(Rtc::new())
+        } else {
+            None
+        };
+
+        let save_path = if header.has_battery && (ram_size > 0 || rtc.is_some()) {
+            save_path
+        } else {
+            None
+        };
+
+        let multicart = matches!(header.cart_type, MbcType::MBC1) && is_mbc1_multicart(&rom_data);
+
+        let mut ram_data = vec![0; ram_size];
+        let save_size_mismatch = match &save_path {
+            Some(path) => load_save_file(path, &mut ram_data, rtc.as_mut())?,
+            None => None,
+        };
 
         Ok(Cartridge {
             header,
             rom_data,
-            ram_data: vec![0; ram_size],
+            ram_data,
+            patch_path: None,
+            save_size_mismatch,
+            save_path,
+            ram_dirty: false,
             // Initialize MBC1 State
             ram_enabled: false,
             rom_bank: 1, // Defaults to 1, not 0
             ram_bank: 0,
             banking_mode: 0,
+            multicart,
+            rtc,
+            rumble: false,
         })
     }
 
+    // Writes external RAM to the .sav file next to the ROM. No-op for cartridges
+    // without a battery or when nothing changed since the last flush.
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        if !self.ram_dirty {
+            return Ok(());
+        }
+
+        let mut data = self.ram_data.clone();
+        if let Some(rtc) = &mut self.rtc {
+            data.extend_from_slice(&rtc.save_bytes());
+        }
+
+        // Write to a temporary file first so a crash mid-write can't corrupt the old save
+        let tmp_path = path.with_extension("sav.tmp");
+        fs::write(&tmp_path, &data)?;
+        fs::rename(&tmp_path, path)?;
+
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    // Identifies the exact ROM image (after patching) a save state belongs to
+    pub fn rom_crc32(&self) -> u32 {
+        crc32fast::hash(&self.rom_data)
+    }
+
+    // Mapper registers, external RAM and the RTC. The ROM itself is not stored.
+    pub fn save_state(&mut self, w: &mut StateWriter) {
+        w.bytes(&self.ram_data);
+        w.bool(self.ram_enabled);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.u8(self.banking_mode);
+        w.bool(self.rumble);
+        if let Some(rtc) = &mut self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes_into(&mut self.ram_data)?;
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u16()?;
+        self.ram_bank = r.u8()?;
+        self.banking_mode = r.u8()?;
+        self.rumble = r.bool()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(r)?;
+        }
+        // The restored RAM replaces what's in the .sav file on the next flush
+        self.ram_dirty = true;
+        Ok(())
+    }
+
     pub fn verify_checksum(&self) -> bool {
-        let mut checksum: u8 = 0;
-        for address in 0x134..=0x14C {
-            checksum = checksum
-                .wrapping_sub(self.rom_data[address])
-                .wrapping_sub(1);
+        self.header_checksum() == self.header.check_sum
+    }
+
+    // Same check as verify_checksum, as an error the caller can report or act on
+    pub fn check_header(&self) -> Result<(), CartridgeError> {
+        let actual = self.header_checksum();
+        if actual != self.header.check_sum {
+            return Err(CartridgeError::BadChecksum {
+                expected: self.header.check_sum,
+                actual,
+            });
         }
-        checksum == self.header.check_sum
+        Ok(())
+    }
+
+    fn header_checksum(&self) -> u8 {
+        header_checksum(&self.rom_data)
+    }
+
+    pub fn verify_global_checksum(&self) -> bool {
+        global_checksum(&self.rom_data) == self.header.global_check_sum
+    }
+
+    pub fn verify_logo(&self) -> bool {
+        has_nintendo_logo(&self.rom_data)
     }
 
     pub fn read(&self, address: u16) -> u8 {
         match self.header.cart_type {
             MbcType::RomOnly => self.read_rom_only(address),
             MbcType::MBC1 => self.read_mbc1(address),
-            _ => 0xFF, // Placeholder for MBC2/3
+            MbcType::MBC2 => self.read_mbc2(address),
+            MbcType::MBC3 => self.read_mbc3(address),
+            MbcType::MBC5 => self.read_mbc5(address),
+            _ => 0xFF,
         }
     }
 
@@ -122,6 +498,9 @@ impl Cartridge {
         match self.header.cart_type {
             MbcType::RomOnly => self.write_rom_only(address, byte),
             MbcType::MBC1 => self.write_mbc1(address, byte),
+            MbcType::MBC2 => self.write_mbc2(address, byte),
+            MbcType::MBC3 => self.write_mbc3(address, byte),
+            MbcType::MBC5 => self.write_mbc5(address, byte),
             _ => {}
         }
     }
@@ -129,12 +508,8 @@ impl Cartridge {
     // --- ROM ONLY Logic ---
     fn read_rom_only(&self, address: u16) -> u8 {
         match address {
-            0x0000..=0x7FFF => {
-                if (address as usize) < self.rom_data.len() {
-                    self.rom_data[address as usize]
-                } else {
-                    0xFF
-                }
+            0x0000..=0x7FFF if (address as usize) < self.rom_data.len() => {
+                self.rom_data[address as usize]
             }
             0xA000..=0xBFFF => self.read_ram_common(address, 0),
             _ => 0xFF,
@@ -154,7 +529,7 @@ impl Cartridge {
             0x0000..=0x3FFF => {
                 let bank = if self.banking_mode == 1 {
                     // Advanced: In RAM banking mode, this can be switched (Multi-cart behavior)
-                    (self.ram_bank << 5) as usize
+                    (self.ram_bank as usize) << self.mbc1_bank_shift()
                 } else {
                     0
                 };
@@ -163,8 +538,11 @@ impl Cartridge {
 
             // ROM Bank 01-7F (4000-7FFF)
             0x4000..=0x7FFF => {
-                // Combine the 2-bit RAM bank (high) and 5-bit ROM bank (low)
-                let bank = ((self.ram_bank << 5) | self.rom_bank) as usize;
+                // Combine the 2-bit RAM bank (high) and 5-bit ROM bank (low).
+                // Multicarts drop bit 4 of the ROM bank, so each game sees 16 banks.
+                let shift = self.mbc1_bank_shift();
+                let rom_bank = self.rom_bank as usize & ((1 << shift) - 1);
+                let bank = ((self.ram_bank as usize) << shift) | rom_bank;
                 self.read_rom_banked(address - 0x4000, bank)
             }
 
@@ -199,7 +577,7 @@ impl Cartridge {
                 if bank == 0 {
                     bank = 1;
                 } // "0 is 1" quirk
-                self.rom_bank = bank;
+                self.rom_bank = bank as u16;
             }
 
             0x4000..=0x5FFF => {
@@ -226,6 +604,217 @@ impl Cartridge {
         }
     }
 
+    fn mbc1_bank_shift(&self) -> usize {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    // --- MBC2 Logic ---
+    // https://gbdev.io/pandocs/MBC2.html
+    fn read_mbc2(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.read_rom_banked(address, 0),
+            0x4000..=0x7FFF => self.read_rom_banked(address - 0x4000, self.rom_bank as usize),
+
+            // Built-in RAM: only the low 9 address bits are decoded, so the 512
+            // cells echo across the whole area. Upper nibbles are open bus.
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                self.ram_data[(address & 0x01FF) as usize] | 0xF0
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write_mbc2(&mut self, address: u16, byte: u8) {
+        match address {
+            // Bit 8 of the address picks the register: clear = RAM enable, set = ROM bank
+            0x0000..=0x3FFF => {
+                if (address & 0x0100) == 0 {
+                    self.ram_enabled = (byte & 0x0F) == 0x0A;
+                } else {
+                    let mut bank = byte & 0x0F;
+                    if bank == 0 {
+                        bank = 1;
+                    }
+                    self.rom_bank = bank as u16;
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                let value = byte & 0x0F;
+                let index = (address & 0x01FF) as usize;
+                if self.ram_data[index] != value {
+                    self.ram_data[index] = value;
+                    self.ram_dirty = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // --- MBC3 Logic ---
+    // https://gbdev.io/pandocs/MBC3.html
+    fn read_mbc3(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.read_rom_banked(address, 0),
+            0x4000..=0x7FFF => self.read_rom_banked(address - 0x4000, self.rom_bank as usize),
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                match self.ram_bank {
+                    0x00..=0x03 => self.read_ram_common(address, self.ram_bank as usize),
+                    0x08..=0x0C => match &self.rtc {
+                        Some(rtc) => rtc.read(self.ram_bank),
+                        None => 0xFF,
+                    },
+                    _ => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write_mbc3(&mut self, address: u16, byte: u8) {
+        match address {
+            // RAM and RTC Enable (0000-1FFF)
+            0x0000..=0x1FFF => {
+                self.ram_enabled = (byte & 0x0F) == 0x0A;
+            }
+
+            // ROM Bank Number (2000-3FFF), all 7 bits
+            0x2000..=0x3FFF => {
+                let mut bank = byte & 0x7F;
+                if bank == 0 {
+                    bank = 1;
+                }
+                self.rom_bank = bank as u16;
+            }
+
+            // RAM Bank Number or RTC Register Select (4000-5FFF)
+            0x4000..=0x5FFF => {
+                self.ram_bank = byte & 0x0F;
+            }
+
+            // Latch Clock Data (6000-7FFF)
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(byte);
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                match self.ram_bank {
+                    0x00..=0x03 => self.write_ram_common(address, byte, self.ram_bank as usize),
+                    0x08..=0x0C => {
+                        // Setting the clock, or halting/starting it through
+                        // DH, changes what the .sav trailer must hold
+                        if let Some(rtc) = &mut self.rtc {
+                            rtc.write(self.ram_bank, byte);
+                            self.ram_dirty = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // --- MBC5 Logic ---
+    // https://gbdev.io/pandocs/MBC5.html
+    fn read_mbc5(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.read_rom_banked(address, 0),
+            // Unlike MBC1/3, bank 0 can be mapped here as well
+            0x4000..=0x7FFF => self.read_rom_banked(address - 0x4000, self.rom_bank as usize),
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                self.read_ram_common(address, self.mbc5_ram_bank())
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write_mbc5(&mut self, address: u16, byte: u8) {
+        match address {
+            // RAM Enable (0000-1FFF), MBC5 decodes the full byte
+            0x0000..=0x1FFF => {
+                self.ram_enabled = byte == 0x0A;
+            }
+
+            // ROM Bank Number low 8 bits (2000-2FFF)
+            0x2000..=0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | byte as u16;
+            }
+
+            // ROM Bank Number bit 8 (3000-3FFF)
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (((byte & 0x01) as u16) << 8);
+            }
+
+            // RAM Bank Number (4000-5FFF)
+            0x4000..=0x5FFF => {
+                self.ram_bank = byte & 0x0F;
+                if self.header.has_rumble {
+                    self.rumble = (byte & 0x08) != 0;
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                self.write_ram_common(address, byte, self.mbc5_ram_bank());
+            }
+            _ => {}
+        }
+    }
+
+    fn mbc5_ram_bank(&self) -> usize {
+        // On rumble carts bit 3 goes to the motor instead of the RAM chip
+        if self.header.has_rumble {
+            (self.ram_bank & 0x07) as usize
+        } else {
+            self.ram_bank as usize
+        }
+    }
+
+    // True while the game is driving the rumble motor
+    // Runs the MBC3 clock on emulated CPU cycles instead of host time, so
+    // headless runs are reproducible. Call before anything reads the clock.
+    pub fn use_emulated_rtc(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.use_emulated_time();
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    pub fn rumble_active(&self) -> bool {
+        self.rumble
+    }
+
     fn read_rom_banked(&self, offset: u16, bank: usize) -> u8 {
         let rom_address = (bank * 0x4000) + offset as usize;
         let final_address = rom_address & (self.rom_data.len() - 1);
@@ -255,8 +844,118 @@ impl Cartridge {
         let ram_address = (bank * 0x2000) + (address - 0xA000) as usize;
         let final_address = ram_address & (self.ram_data.len() - 1);
 
-        if final_address < self.ram_data.len() {
+        if final_address < self.ram_data.len() && self.ram_data[final_address] != byte {
             self.ram_data[final_address] = byte;
+            self.ram_dirty = true;
+        }
+    }
+}
+
+// game.gb.gz -> game.gb, so companion files (.sav, save states, patches) are
+// named game.sav rather than game.gb.sav
+pub fn strip_gzip_extension(rom_path: &Path) -> PathBuf {
+    let is_gzip = rom_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+    if is_gzip {
+        rom_path.with_extension("")
+    } else {
+        rom_path.to_path_buf()
+    }
+}
+
+// game.gb -> the first of game.bps / game.ups / game.ips that exists
+fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+// The checks below work on a raw image, which must be at least a header long
+
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..=0x14C].iter().fold(0u8, |checksum, byte| {
+        checksum.wrapping_sub(*byte).wrapping_sub(1)
+    })
+}
+
+// Sum of every ROM byte except the checksum itself. Real hardware never
+// checks this, but a mismatch is a good sign of a bad dump or a patched ROM.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    let mut checksum: u16 = 0;
+    for (address, byte) in rom.iter().enumerate() {
+        if address != 0x14E && address != 0x14F {
+            checksum = checksum.wrapping_add(*byte as u16);
         }
     }
+    checksum
+}
+
+// The boot ROM refuses to start a cartridge whose logo doesn't match
+pub fn has_nintendo_logo(rom: &[u8]) -> bool {
+    rom[0x104..0x134] == NINTENDO_LOGO
+}
+
+// Bank reads mask the address with `len - 1`, so the image has to be a power of
+// two. Odd-sized images (the 1.1/1.2/1.5 MiB header sizes, or dumps with junk
+// past the end of the ROM) are padded with 0xFF, which is what an unconnected
+// ROM address line reads as. Images shorter than their header claims are
+// rejected by `load` before this runs.
+fn pad_rom(rom: &mut Vec<u8>) {
+    let size = rom.len().max(0x8000).next_power_of_two();
+    rom.resize(size, 0xFF);
+}
+
+// MBC1M boards are 1 MiB images made of four 256 KiB games (a menu plus the
+// titles), each starting with its own header. Seeing the Nintendo logo at more
+// than one of those boundaries is a reliable way to tell them from plain MBC1.
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    if rom.len() != 1024 * 1024 {
+        return false;
+    }
+
+    let logos = (0..4)
+        .map(|game| game * 0x40000 + 0x104)
+        .filter(|&start| rom[start..start + NINTENDO_LOGO.len()] == NINTENDO_LOGO)
+        .count();
+    logos > 1
+}
+
+// Loads a .sav file into `ram`. A missing file is fine (first boot). Files that
+// don't match the header's RAM size are copied as far as they overlap and
+// reported back. For RTC cartridges the clock trailer that follows the RAM
+// image is restored as well.
+fn load_save_file(
+    path: &Path,
+    ram: &mut [u8],
+    rtc: Option<&mut Rtc>,
+) -> Result<Option<SaveSizeMismatch>, CartridgeError> {
+    let mut data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(CartridgeError::Io(e)),
+    };
+
+    if let Some(rtc) = rtc {
+        // Accept both the 48-byte trailer and the older 44-byte one
+        for trailer_size in [RTC_SAVE_SIZE, RTC_SAVE_SIZE_LEGACY] {
+            if data.len() >= trailer_size && data.len() - trailer_size == ram.len() {
+                let split = data.len() - trailer_size;
+                rtc.load_save_bytes(&data[split..]);
+                data.truncate(split);
+                break;
+            }
+        }
+    }
+
+    let mismatch = (data.len() != ram.len()).then(|| SaveSizeMismatch {
+        path: path.to_path_buf(),
+        actual: data.len(),
+        expected: ram.len(),
+    });
+
+    let len = data.len().min(ram.len());
+    ram[..len].copy_from_slice(&data[..len]);
+    Ok(mismatch)
 }
@@ -1,12 +1,21 @@
 use crate::bus::Bus;
 use crate::interrupts::Interrupt;
+use crate::memory_bus::MemoryBus;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 const Z_FLAG: u8 = 0b1000_0000;
 const N_FLAG: u8 = 0b0100_0000;
 const H_FLAG: u8 = 0b0010_0000;
 const C_FLAG: u8 = 0b0001_0000;
 
-pub struct Cpu {
-    pub bus: Bus,
+// The 11 unused opcodes hang the CPU until power off; nothing wakes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lockup {
+    pub opcode: u8,
+    pub address: u16,
+}
+
+pub struct Cpu<B: MemoryBus = Bus> {
+    pub bus: B,
     // https://gbdev.io/pandocs/CPU_Registers_and_Flags.html
     pub a: u8,
     pub b: u8,
@@ -19,11 +28,21 @@ pub struct Cpu {
     pub pc: u16,
     pub f: u8,
     pub ime: bool,
+    // EI takes effect only after the instruction that follows it
+    pub ime_pending: bool,
     pub is_sleeping: bool,
+    // STOP halts the whole system until a joypad button is pressed
+    pub is_stopped: bool,
+    // HALT with IME=0 and an interrupt already pending doesn't halt; the CPU
+    // fails to advance PC past the next opcode instead
+    halt_bug: bool,
+    pub lockup: Option<Lockup>,
+    // M-cycles the current instruction has spent so far
+    m_cycles: u8,
 }
 
-impl Cpu {
-    pub fn new(bus: Bus) -> Self {
+impl<B: MemoryBus> Cpu<B> {
+    pub fn new(bus: B) -> Self {
         Cpu {
             bus,
             a: 0x01,
@@ -37,7 +56,12 @@ impl Cpu {
             sp: 0xFFFE,
             pc: 0x0100,
             ime: false,
+            ime_pending: false,
             is_sleeping: false,
+            is_stopped: false,
+            halt_bug: false,
+            lockup: None,
+            m_cycles: 0,
         }
     }
     pub fn get_z(&self) -> bool {
@@ -54,30 +78,30 @@ impl Cpu {
     }
     pub fn set_z(&mut self, value: bool) {
         if value {
-            self.f = self.f | Z_FLAG;
+            self.f |= Z_FLAG;
         } else {
-            self.f = self.f & !Z_FLAG;
+            self.f &= !Z_FLAG;
         }
     }
     pub fn set_n(&mut self, value: bool) {
         if value {
-            self.f = self.f | N_FLAG;
+            self.f |= N_FLAG;
         } else {
-            self.f = self.f & !N_FLAG;
+            self.f &= !N_FLAG;
         }
     }
     pub fn set_h(&mut self, value: bool) {
         if value {
-            self.f = self.f | H_FLAG;
+            self.f |= H_FLAG;
         } else {
-            self.f = self.f & !H_FLAG;
+            self.f &= !H_FLAG;
         }
     }
     pub fn set_c(&mut self, value: bool) {
         if value {
-            self.f = self.f | C_FLAG;
+            self.f |= C_FLAG;
         } else {
-            self.f = self.f & !C_FLAG;
+            self.f &= !C_FLAG;
         }
     }
 
@@ -113,43 +137,121 @@ impl Cpu {
         self.l = (value & 0x00FF) as u8;
     }
 
-    pub fn check_interrupts(&mut self) {
-        let int_flag = self.bus.int_flag;
-        let ie_reg = self.bus.ie_reg;
+    // Returns the cycles spent dispatching an interrupt, if one was serviced
+    pub fn check_interrupts(&mut self) -> u8 {
+        if self.lockup.is_some() || self.is_stopped {
+            return 0;
+        }
+        let pending = self.bus.pending_interrupts();
         for interrupt in Interrupt::iterate() {
-            let mask = interrupt.mask();
-            if (int_flag & mask) != 0 && (ie_reg & mask) != 0 {
+            if (pending & interrupt.mask()) != 0 {
                 self.is_sleeping = false;
                 if self.ime {
+                    self.m_cycles = 0;
                     self.handle_interrupt(interrupt);
+                    return self.m_cycles * 4;
                 }
-                return;
+                return 0;
             }
         }
+        0
     }
+    // Two idle M-cycles (the second one is push_stack's), the two pushes and
+    // one more to load the vector: 20 cycles
     pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
         self.ime = false;
-        self.bus.int_flag &= !interrupt.mask();
+        self.bus.acknowledge_interrupt(interrupt);
+        self.idle();
+        // EI; HALT hit the HALT bug: the handler returns to the HALT itself
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
         self.push_stack(self.pc);
         self.pc = interrupt.handler_address();
+        self.idle();
+    }
+
+    // Registers only; the bus is saved separately
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for reg in [
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ] {
+            w.u8(reg);
+        }
+        w.u16(self.sp);
+        w.u16(self.pc);
+        w.bool(self.ime);
+        w.bool(self.ime_pending);
+        w.bool(self.is_sleeping);
+        w.bool(self.is_stopped);
+        w.bool(self.halt_bug);
+        let lockup = self.lockup.unwrap_or(Lockup {
+            opcode: 0,
+            address: 0,
+        });
+        w.bool(self.lockup.is_some());
+        w.u8(lockup.opcode);
+        w.u16(lockup.address);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.a = r.u8()?;
+        self.f = r.u8()? & 0xF0;
+        self.b = r.u8()?;
+        self.c = r.u8()?;
+        self.d = r.u8()?;
+        self.e = r.u8()?;
+        self.h = r.u8()?;
+        self.l = r.u8()?;
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        self.ime = r.bool()?;
+        self.ime_pending = r.bool()?;
+        self.is_sleeping = r.bool()?;
+        self.is_stopped = r.bool()?;
+        self.halt_bug = r.bool()?;
+        let locked = r.bool()?;
+        let lockup = Lockup {
+            opcode: r.u8()?,
+            address: r.u16()?,
+        };
+        self.lockup = locked.then_some(lockup);
+        Ok(())
     }
 }
 
 //Helpers functions here
-impl Cpu {
+impl<B: MemoryBus> Cpu<B> {
+    // Every memory access takes one M-cycle; the rest of the system runs
+    // through it before the access lands, so mid-instruction reads of LY, DIV,
+    // TIMA and friends see up-to-date values
+    fn read(&mut self, address: u16) -> u8 {
+        self.idle();
+        self.bus.read(address)
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        self.idle();
+        self.bus.write(address, value);
+    }
+    // An M-cycle with no memory access
+    fn idle(&mut self) {
+        self.bus.tick();
+        self.m_cycles += 1;
+    }
     fn xor_a(&mut self, value: u8) {
         self.a ^= value;
         self.f = 0;
         self.set_z(self.a == 0);
     }
     fn next_u8(&mut self) -> u8 {
-        let val = self.bus.read_byte(self.pc);
+        let val = self.read(self.pc);
         self.pc = self.pc.wrapping_add(1);
         val
     }
     fn next_u16(&mut self) -> u16 {
-        let low = self.bus.read_byte(self.pc) as u16;
-        let high = self.bus.read_byte(self.pc + 1) as u16;
+        let low = self.read(self.pc) as u16;
+        let high = self.read(self.pc.wrapping_add(1)) as u16;
         self.pc = self.pc.wrapping_add(2);
         high << 8 | low
     }
@@ -169,7 +271,7 @@ impl Cpu {
         result
     }
     fn jr(&mut self, condition: bool) -> u8 {
-        let offset = self.bus.read_byte(self.pc) as i8;
+        let offset = self.read(self.pc) as i8;
         self.pc = self.pc.wrapping_add(1);
         if condition {
             self.pc = self.pc.wrapping_add(offset as u16);
@@ -188,6 +290,16 @@ impl Cpu {
             12
         }
     }
+    fn ret(&mut self, condition: bool) -> u8 {
+        // Checking the condition costs an M-cycle of its own
+        self.idle();
+        if condition {
+            self.pc = self.pop_stack();
+            20
+        } else {
+            8
+        }
+    }
     fn add_hl(&mut self, value: u16) {
         let hl = self.get_hl();
         let (result, carr_cby) = hl.overflowing_add(value);
@@ -212,17 +324,20 @@ impl Cpu {
         self.set_h(false);
         self.set_c(false);
     }
+    // Includes the internal M-cycle that PUSH, CALL, RST and interrupts all
+    // spend before the first write
     fn push_stack(&mut self, value: u16) {
+        self.idle();
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write(self.sp, ((value & 0xFF00) >> 8) as u8);
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0x00FF) as u8);
+        self.write(self.sp, (value & 0x00FF) as u8);
     }
 
     fn pop_stack(&mut self) -> u16 {
-        let low = self.bus.read_byte(self.sp) as u16;
+        let low = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
-        let high = self.bus.read_byte(self.sp) as u16;
+        let high = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
         (high << 8) | low
     }
@@ -309,7 +424,7 @@ impl Cpu {
 }
 
 //Helpers for CB instructions
-impl Cpu {
+impl<B: MemoryBus> Cpu<B> {
     fn get_cb_reg(&mut self, index: u8) -> u8 {
         match index {
             0 => self.b,
@@ -318,7 +433,7 @@ impl Cpu {
             3 => self.e,
             4 => self.h,
             5 => self.l,
-            6 => self.bus.read_byte(self.get_hl()),
+            6 => self.read(self.get_hl()),
             7 => self.a,
             _ => unreachable!(),
         }
@@ -332,7 +447,7 @@ impl Cpu {
             3 => self.e = val,
             4 => self.h = val,
             5 => self.l = val,
-            6 => self.bus.write_byte(self.get_hl(), val),
+            6 => self.write(self.get_hl(), val),
             7 => self.a = val,
             _ => unreachable!(),
         }
@@ -433,20 +548,47 @@ impl Cpu {
 // Step function and instructions here
 // https://gbdev.io/gb-opcodes/optables/
 // https://rgbds.gbdev.io/docs/v1.0.1/gbz80.7
-impl Cpu {
+impl<B: MemoryBus> Cpu<B> {
     pub fn step(&mut self) -> u8 {
-        if self.is_sleeping {
+        self.m_cycles = 0;
+        if self.is_stopped {
+            // The system clock is stopped as well, so nothing else advances
+            if !self.bus.joypad_input() {
+                return 4;
+            }
+            self.is_stopped = false;
+        }
+        if self.is_sleeping || self.lockup.is_some() {
+            self.idle();
             return 4;
         }
-        let opcode = self.bus.read_byte(self.pc);
-        self.pc = self.pc.wrapping_add(1);
+        let enable_ime = self.ime_pending;
+        let cycles = self.execute();
+        // A DI right after EI cancels it
+        if enable_ime && self.ime_pending {
+            self.ime = true;
+            self.ime_pending = false;
+        }
+        // Whatever the instruction didn't spend on memory accesses is internal
+        // work at its end (16-bit arithmetic, taken jumps, RET)
+        while self.m_cycles * 4 < cycles {
+            self.idle();
+        }
+        debug_assert_eq!(self.m_cycles * 4, cycles);
+        cycles
+    }
+
+    fn execute(&mut self) -> u8 {
+        let opcode = self.read(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
         match opcode {
             0x00 => 4, //NOP
             0xC3 => {
-                let low = self.bus.read_byte(self.pc) as u16;
-                let high = self.bus.read_byte(self.pc + 1) as u16;
-                let target = high << 8 | low;
-                self.pc = target;
+                self.pc = self.next_u16();
                 16
             }
             0xA8 => {
@@ -476,7 +618,7 @@ impl Cpu {
 
             0xAE => {
                 let hl = self.get_hl();
-                let val = self.bus.read_byte(hl);
+                let val = self.read(hl);
                 self.xor_a(val);
                 8
             }
@@ -497,7 +639,7 @@ impl Cpu {
 
             0x32 => {
                 let hl = self.get_hl();
-                self.bus.write_byte(hl, self.a);
+                self.write(hl, self.a);
                 self.set_hl(hl.wrapping_sub(1));
                 8
             }
@@ -536,9 +678,9 @@ impl Cpu {
             // Read from memory, decrement, write back.
             0x35 => {
                 let hl = self.get_hl();
-                let val = self.bus.read_byte(hl);
+                let val = self.read(hl);
                 let result = self.dec(val);
-                self.bus.write_byte(hl, result);
+                self.write(hl, result);
                 12
             }
             // --- JR Family (Jump Relative) ---
@@ -605,15 +747,19 @@ impl Cpu {
 
             0x08 => {
                 let address = self.next_u16();
-                self.bus.write_byte(address, (self.sp & 0x00FF) as u8);
-                self.bus
-                    .write_byte(address.wrapping_add(1), ((self.sp & 0xFF00) >> 8) as u8);
+                self.write(address, (self.sp & 0x00FF) as u8);
+                self.write(address.wrapping_add(1), ((self.sp & 0xFF00) >> 8) as u8);
                 20
             }
 
             0x10 => {
-                self.next_u8();
-                self.is_sleeping = true;
+                // STOP is two bytes but only spends one M-cycle. With a button
+                // already held it is skipped, as the wake-up condition is met.
+                self.pc = self.pc.wrapping_add(1);
+                if !self.bus.joypad_input() {
+                    self.bus.stop();
+                    self.is_stopped = true;
+                }
                 4
             }
 
@@ -650,31 +796,32 @@ impl Cpu {
             0x36 => {
                 let val = self.next_u8();
                 let hl = self.get_hl();
-                self.bus.write_byte(hl, val);
+                self.write(hl, val);
                 12
             }
             // 0xF3: DI (Disable Interrupts)
             0xF3 => {
                 self.ime = false;
+                self.ime_pending = false;
                 4
             }
 
             // 0xFB: EI (Enable Interrupts)
             0xFB => {
-                self.ime = true;
+                self.ime_pending = true;
                 4
             }
             0xE0 => {
                 let offset = self.next_u8() as u16;
                 let address = 0xFF00 | offset;
-                self.bus.write_byte(address, self.a);
+                self.write(address, self.a);
                 12
             }
             // 0xE2: LD (C), A
             // Write A to (0xFF00 + C)
             0xE2 => {
                 let address = 0xFF00 | (self.c as u16);
-                self.bus.write_byte(address, self.a);
+                self.write(address, self.a);
                 8
             }
 
@@ -682,12 +829,12 @@ impl Cpu {
             // Read from (0xFF00 + C) into A
             0xF2 => {
                 let address = 0xFF00 | (self.c as u16);
-                self.a = self.bus.read_byte(address);
+                self.a = self.read(address);
                 8
             }
             0xF0 => {
                 let address = 0xFF00 | self.next_u8() as u16;
-                self.a = self.bus.read_byte(address);
+                self.a = self.read(address);
                 12
             }
             // --- CP r8 Family (Compare A with r8) ---
@@ -724,7 +871,7 @@ impl Cpu {
             // Compare A with value in memory at HL
             0xBE => {
                 let hl = self.get_hl();
-                let val = self.bus.read_byte(hl);
+                let val = self.read(hl);
                 self.cp(val);
                 8
             }
@@ -738,17 +885,17 @@ impl Cpu {
             }
             0xEA => {
                 let address = self.next_u16();
-                self.bus.write_byte(address, self.a);
+                self.write(address, self.a);
                 16
             }
             0xFA => {
                 let address = self.next_u16();
-                self.a = self.bus.read_byte(address);
+                self.a = self.read(address);
                 16
             }
             0x2A => {
                 let hl = self.get_hl();
-                self.a = self.bus.read_byte(hl);
+                self.a = self.read(hl);
                 self.set_hl(hl.wrapping_add(1));
                 8
             }
@@ -822,7 +969,7 @@ impl Cpu {
             }
             0x7E => {
                 let hl = self.get_hl();
-                self.a = self.bus.read_byte(hl);
+                self.a = self.read(hl);
                 8
             }
             0x7F => 4,
@@ -857,7 +1004,7 @@ impl Cpu {
             }
             0xB6 => {
                 let hl = self.get_hl();
-                let val = self.bus.read_byte(hl);
+                let val = self.read(hl);
                 self.or(val);
                 8
             }
@@ -872,41 +1019,13 @@ impl Cpu {
                 16
             }
 
-            0xC0 => {
-                if !self.get_z() {
-                    self.pc = self.pop_stack();
-                    20
-                } else {
-                    8
-                }
-            }
+            0xC0 => self.ret(!self.get_z()),
 
-            0xC8 => {
-                if self.get_z() {
-                    self.pc = self.pop_stack();
-                    20
-                } else {
-                    8
-                }
-            }
+            0xC8 => self.ret(self.get_z()),
 
-            0xD0 => {
-                if !self.get_c() {
-                    self.pc = self.pop_stack();
-                    20
-                } else {
-                    8
-                }
-            }
+            0xD0 => self.ret(!self.get_c()),
 
-            0xD8 => {
-                if self.get_c() {
-                    self.pc = self.pop_stack();
-                    20
-                } else {
-                    8
-                }
-            }
+            0xD8 => self.ret(self.get_c()),
 
             0xD9 => {
                 self.pc = self.pop_stack();
@@ -987,9 +1106,9 @@ impl Cpu {
             }
             0x34 => {
                 let hl = self.get_hl();
-                let val = self.bus.read_byte(hl);
+                let val = self.read(hl);
                 let result = self.inc(val);
-                self.bus.write_byte(hl, result);
+                self.write(hl, result);
                 12
             }
 
@@ -1018,7 +1137,7 @@ impl Cpu {
                 4
             }
             0x46 => {
-                self.b = self.bus.read_byte(self.get_hl());
+                self.b = self.read(self.get_hl());
                 8
             }
             0x47 => {
@@ -1049,7 +1168,7 @@ impl Cpu {
                 4
             }
             0x4E => {
-                self.c = self.bus.read_byte(self.get_hl());
+                self.c = self.read(self.get_hl());
                 8
             }
             0x4F => {
@@ -1080,7 +1199,7 @@ impl Cpu {
                 4
             }
             0x56 => {
-                self.d = self.bus.read_byte(self.get_hl());
+                self.d = self.read(self.get_hl());
                 8
             }
             0x57 => {
@@ -1111,7 +1230,7 @@ impl Cpu {
                 4
             }
             0x5E => {
-                self.e = self.bus.read_byte(self.get_hl());
+                self.e = self.read(self.get_hl());
                 8
             }
             0x5F => {
@@ -1142,7 +1261,7 @@ impl Cpu {
                 4
             }
             0x66 => {
-                self.h = self.bus.read_byte(self.get_hl());
+                self.h = self.read(self.get_hl());
                 8
             }
             0x67 => {
@@ -1173,7 +1292,7 @@ impl Cpu {
             }
             0x6D => 4,
             0x6E => {
-                self.l = self.bus.read_byte(self.get_hl());
+                self.l = self.read(self.get_hl());
                 8
             }
             0x6F => {
@@ -1183,32 +1302,32 @@ impl Cpu {
 
             // Destination (HL) (0x70 - 0x77)
             0x70 => {
-                self.bus.write_byte(self.get_hl(), self.b);
+                self.write(self.get_hl(), self.b);
                 8
             }
             0x71 => {
-                self.bus.write_byte(self.get_hl(), self.c);
+                self.write(self.get_hl(), self.c);
                 8
             }
             0x72 => {
-                self.bus.write_byte(self.get_hl(), self.d);
+                self.write(self.get_hl(), self.d);
                 8
             }
             0x73 => {
-                self.bus.write_byte(self.get_hl(), self.e);
+                self.write(self.get_hl(), self.e);
                 8
             }
             0x74 => {
-                self.bus.write_byte(self.get_hl(), self.h);
+                self.write(self.get_hl(), self.h);
                 8
             }
             0x75 => {
-                self.bus.write_byte(self.get_hl(), self.l);
+                self.write(self.get_hl(), self.l);
                 8
             }
 
             0x77 => {
-                self.bus.write_byte(self.get_hl(), self.a);
+                self.write(self.get_hl(), self.a);
                 8
             }
 
@@ -1246,7 +1365,11 @@ impl Cpu {
                 16
             }
             0x76 => {
-                self.is_sleeping = true;
+                if !self.ime && self.bus.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.is_sleeping = true;
+                }
                 4
             }
             0x03 => {
@@ -1318,7 +1441,7 @@ impl Cpu {
                 4
             }
             0xA6 => {
-                let val = self.bus.read_byte(self.get_hl());
+                let val = self.read(self.get_hl());
                 self.and_a(val);
                 8
             }
@@ -1399,19 +1522,19 @@ impl Cpu {
 
             // --- Indirect Load Family ---
             0x02 => {
-                self.bus.write_byte(self.get_bc(), self.a);
+                self.write(self.get_bc(), self.a);
                 8
             }
             0x12 => {
-                self.bus.write_byte(self.get_de(), self.a);
+                self.write(self.get_de(), self.a);
                 8
             }
             0x0A => {
-                self.a = self.bus.read_byte(self.get_bc());
+                self.a = self.read(self.get_bc());
                 8
             }
             0x1A => {
-                self.a = self.bus.read_byte(self.get_de());
+                self.a = self.read(self.get_de());
                 8
             }
 
@@ -1441,7 +1564,7 @@ impl Cpu {
                 4
             }
             0x86 => {
-                let val = self.bus.read_byte(self.get_hl());
+                let val = self.read(self.get_hl());
                 self.add(val);
                 8
             }
@@ -1479,7 +1602,7 @@ impl Cpu {
                 4
             }
             0x8E => {
-                let val = self.bus.read_byte(self.get_hl());
+                let val = self.read(self.get_hl());
                 self.adc(val);
                 8
             }
@@ -1496,18 +1619,18 @@ impl Cpu {
 
             0x22 => {
                 let hl = self.get_hl();
-                self.bus.write_byte(hl, self.a);
+                self.write(hl, self.a);
                 self.set_hl(hl.wrapping_add(1));
                 8
             }
             0x3A => {
                 let hl = self.get_hl();
-                self.a = self.bus.read_byte(hl);
+                self.a = self.read(hl);
                 self.set_hl(hl.wrapping_sub(1));
                 8
             }
-            0xC4 => self.call(self.get_z() == false),
-            0xD4 => self.call(self.get_c() == false),
+            0xC4 => self.call(!self.get_z()),
+            0xD4 => self.call(!self.get_c()),
             0xCC => self.call(self.get_z()),
             0xDC => self.call(self.get_c()),
             0x1F => {
@@ -1544,7 +1667,7 @@ impl Cpu {
                 4
             }
             0x96 => {
-                let val = self.bus.read_byte(self.get_hl());
+                let val = self.read(self.get_hl());
                 self.sub(val);
                 8
             }
@@ -1585,7 +1708,7 @@ impl Cpu {
                 4
             }
             0x9E => {
-                let val = self.bus.read_byte(self.get_hl());
+                let val = self.read(self.get_hl());
                 self.sbc(val);
                 8
             }
@@ -1633,8 +1756,12 @@ impl Cpu {
             }
 
             0xCB => self.step_cb(),
+            // Illegal: 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
             _ => {
-                println!("Unknown Opcode: {:#02X} at {:#04X}", opcode, self.pc - 1);
+                self.lockup = Some(Lockup {
+                    opcode,
+                    address: self.pc.wrapping_sub(1),
+                });
                 4
             }
         }
@@ -1643,7 +1770,7 @@ impl Cpu {
 
 // CB instructions
 
-impl Cpu {
+impl<B: MemoryBus> Cpu<B> {
     pub fn step_cb(&mut self) -> u8 {
         let opcode = self.next_u8();
         let reg_idx = opcode & 0x07;
@@ -1,7 +1,21 @@
+use std::ops::Range;
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// 160 bytes, one per M-cycle
+const TRANSFER_CYCLES: u16 = 160 * 4;
+
 pub struct Dma {
     pub active: bool,
     pub byte: u8,
     pub start_delay: u8,
+    cycles_left: u16,
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Dma {
@@ -10,16 +24,52 @@ impl Dma {
             active: false,
             byte: 0,
             start_delay: 0,
+            cycles_left: 0,
         }
     }
 
     pub fn start(&mut self, value: u8) {
         self.active = true;
         self.byte = value;
-        self.start_delay = 2; 
+        self.start_delay = 2;
+        self.cycles_left = TRANSFER_CYCLES + self.start_delay as u16 * 4;
+    }
+
+    // Advances the transfer and returns the indices of the bytes due to be
+    // copied in that time. OAM stays locked until the last one is done.
+    pub fn tick(&mut self, cycles: u8) -> Range<u16> {
+        if !self.active {
+            return 0..0;
+        }
+        let before = self.bytes_copied();
+        self.cycles_left = self.cycles_left.saturating_sub(cycles as u16);
+        if self.cycles_left == 0 {
+            self.active = false;
+        }
+        before..self.bytes_copied()
+    }
+
+    // One byte per M-cycle once the start delay has passed
+    fn bytes_copied(&self) -> u16 {
+        (TRANSFER_CYCLES - self.cycles_left.min(TRANSFER_CYCLES)) / 4
     }
 
     pub fn is_transferring(&self) -> bool {
         self.active
     }
-}
\ No newline at end of file
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.active);
+        w.u8(self.byte);
+        w.u8(self.start_delay);
+        w.u16(self.cycles_left);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.active = r.bool()?;
+        self.byte = r.u8()?;
+        self.start_delay = r.u8()?;
+        self.cycles_left = r.u16()?;
+        Ok(())
+    }
+}
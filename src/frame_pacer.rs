@@ -0,0 +1,82 @@
+// Paces emulation to the DMG's real refresh rate (4194304 Hz / 70224 cycles
+// per frame = ~59.73 Hz) from the host's monotonic clock, rather than relying
+// on the window's 60 FPS limiter.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gameboy_emulator::{CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+
+// Most frames in a row we'll emulate without presenting when behind
+const MAX_FRAMESKIP: u32 = 4;
+// Further behind than this and we stop trying to catch up (e.g. after a stall)
+const MAX_LAG: Duration = Duration::from_millis(250);
+// thread::sleep can overshoot by a scheduler tick, so the last stretch is spun
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+const SPEED_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct FramePacer {
+    frame_duration: Duration,
+    deadline: Instant,
+    skipped: u32,
+    measure_start: Instant,
+    measured_frames: u32,
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        let frame_duration =
+            Duration::from_nanos(CYCLES_PER_FRAME as u64 * 1_000_000_000 / CPU_CLOCK_HZ as u64);
+        FramePacer {
+            frame_duration,
+            deadline: now + frame_duration,
+            skipped: 0,
+            measure_start: now,
+            measured_frames: 0,
+        }
+    }
+
+    // Called after emulating a frame. Returns false if it should not be drawn
+    // because we're more than a frame behind schedule.
+    pub fn should_present(&mut self) -> bool {
+        let late = Instant::now() > self.deadline + self.frame_duration;
+        if late && self.skipped < MAX_FRAMESKIP {
+            self.skipped += 1;
+            return false;
+        }
+        self.skipped = 0;
+        true
+    }
+
+    // Sleeps until the current frame's slot is over. Returns the emulation
+    // speed as a percentage of real hardware whenever a new measurement is ready.
+    pub fn wait(&mut self) -> Option<u32> {
+        let mut now = Instant::now();
+        if now < self.deadline {
+            let remaining = self.deadline - now;
+            if remaining > SPIN_THRESHOLD {
+                thread::sleep(remaining - SPIN_THRESHOLD);
+            }
+            while Instant::now() < self.deadline {
+                std::hint::spin_loop();
+            }
+            now = Instant::now();
+        }
+
+        self.deadline += self.frame_duration;
+        if now > self.deadline + MAX_LAG {
+            self.deadline = now + self.frame_duration;
+        }
+
+        self.measured_frames += 1;
+        let elapsed = now - self.measure_start;
+        if elapsed < SPEED_UPDATE_INTERVAL {
+            return None;
+        }
+        let emulated = self.frame_duration * self.measured_frames;
+        let speed = emulated.as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+        self.measure_start = now;
+        self.measured_frames = 0;
+        Some(speed.round() as u32)
+    }
+}
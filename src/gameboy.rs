@@ -0,0 +1,193 @@
+// Headless facade over the whole machine, for frontends and tools that
+// embed the emulator. Nothing here knows about windows or audio devices.
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::cpu::{Cpu, Lockup};
+use crate::interrupts::Interrupt;
+use crate::joypad::Button;
+use crate::ppu::Renderer;
+use crate::savestate::{SaveStateError, StateReader, StateWriter, FORMAT_VERSION, MAGIC};
+use crate::screenshot;
+use crate::serial::SerialSink;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const CPU_CLOCK_HZ: u32 = 4_194_304;
+// 154 lines * 456 dots
+pub const CYCLES_PER_FRAME: u32 = 70224;
+// Magic, format version and ROM CRC32 at the start of a save state
+const HEADER_SIZE: usize = 12;
+
+pub struct GameBoy {
+    pub cpu: Cpu,
+    // Cycles the last frame overshot by, credited to the next one
+    frame_overshoot: u32,
+}
+
+impl GameBoy {
+    pub fn new(cartridge: Cartridge) -> Self {
+        GameBoy {
+            cpu: Cpu::new(Bus::new(cartridge)),
+            frame_overshoot: 0,
+        }
+    }
+
+    // Runs one CPU instruction (or one idle HALT step) plus any interrupt
+    // dispatch after it. The CPU advances the rest of the system M-cycle by
+    // M-cycle as it goes. Returns the cycles taken.
+    pub fn step_instruction(&mut self) -> u8 {
+        let cycles = self.cpu.step();
+        cycles + self.cpu.check_interrupts()
+    }
+
+    // Runs until a full frame's worth of cycles has elapsed
+    pub fn run_frame(&mut self) {
+        let mut cycles_this_frame = self.frame_overshoot;
+        while cycles_this_frame < CYCLES_PER_FRAME {
+            cycles_this_frame += self.step_instruction() as u32;
+        }
+        self.frame_overshoot = cycles_this_frame - CYCLES_PER_FRAME;
+    }
+
+    // 160x144 pixels, row-major, 0xAARRGGBB
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.cpu.bus.ppu.buffer
+    }
+
+    pub fn screenshot_png(&self) -> Vec<u8> {
+        screenshot::encode_png(self.framebuffer(), SCREEN_WIDTH, SCREEN_HEIGHT)
+    }
+
+    // Picks the PPU renderer; takes effect from the next scanline
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.cpu.bus.ppu.renderer = renderer;
+    }
+
+    // Lets the CPU reach VRAM/OAM in every PPU mode and during OAM DMA, for
+    // debugging games that touch them at the wrong time
+    pub fn set_access_blocking(&mut self, enabled: bool) {
+        self.cpu.bus.ppu.access_blocking = enabled;
+    }
+
+    // Lifts the 10-sprites-per-line hardware limit, removing flicker in games
+    // that multiplex sprites (at the cost of accuracy)
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.cpu.bus.ppu.unlimited_sprites = !enabled;
+    }
+
+    // CRC32 of the framebuffer, for checking a run reached an expected picture
+    pub fn framebuffer_hash(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for pixel in self.framebuffer() {
+            hasher.update(&pixel.to_le_bytes());
+        }
+        hasher.finalize()
+    }
+
+    // Audio is off until a sample rate is set, so headless users pay nothing for it
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.cpu.bus.apu.set_sample_rate(rate);
+    }
+
+    // Interleaved stereo samples (left, right, ...) produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    // Connects something to the link port; `None` leaves the cable unplugged
+    pub fn set_serial_sink(&mut self, sink: Option<Box<dyn SerialSink>>) {
+        self.cpu.bus.serial.set_sink(sink);
+    }
+
+    // Bytes sent over the link port since the last call
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.cpu.bus.serial.take_output()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.cpu.bus.joypad.set_button(button as u8, pressed) {
+            self.cpu.bus.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.cpu.bus.cartridge().header.title
+    }
+
+    pub fn save_ram(&mut self) -> std::io::Result<()> {
+        self.cpu.bus.save_ram()
+    }
+
+    // Snapshot of the entire machine. See savestate.rs for the layout.
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for byte in MAGIC {
+            w.u8(byte);
+        }
+        w.u32(FORMAT_VERSION);
+        w.u32(self.cpu.bus.cartridge().rom_crc32());
+
+        w.u32(self.frame_overshoot);
+        self.cpu.save_state(&mut w);
+        self.cpu.bus.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    // Restores a snapshot taken with `save_state`. Any state that's refused (from
+    // another ROM or format version, truncated or corrupt) leaves the machine
+    // untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        for byte in magic.iter_mut() {
+            *byte = r.u8().map_err(|_| SaveStateError::BadMagic)?;
+        }
+        if magic != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        if r.u32()? != self.cpu.bus.cartridge().rom_crc32() {
+            return Err(SaveStateError::WrongRom);
+        }
+
+        // For a given ROM every state has the same size, so comparing against a
+        // fresh snapshot catches truncated files before anything is overwritten
+        let backup = self.save_state();
+        if data.len() != backup.len() {
+            return Err(SaveStateError::Truncated);
+        }
+
+        // A state can still be rejected halfway through (say, a bad enum tag),
+        // so put the machine back the way it was rather than half-loaded
+        let result = self.load_components(&mut r);
+        if result.is_err() {
+            let mut r = StateReader::new(&backup[HEADER_SIZE..]);
+            self.load_components(&mut r)
+                .expect("a snapshot of the running machine always loads");
+        }
+        result
+    }
+
+    fn load_components(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.frame_overshoot = r.u32()?;
+        self.cpu.load_state(r)?;
+        self.cpu.bus.load_state(r)?;
+        if !r.is_empty() {
+            return Err(SaveStateError::Truncated);
+        }
+        Ok(())
+    }
+
+    // Set once the game has executed an illegal opcode. The machine keeps
+    // running (the screen and timers stay alive) but the CPU never recovers.
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.cpu.lockup
+    }
+
+    pub fn rumble_active(&self) -> bool {
+        self.cpu.bus.rumble_active()
+    }
+}
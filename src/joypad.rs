@@ -1,3 +1,18 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// Bit numbers match the ones `Joypad::set_button` takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
+}
+
 pub struct Joypad {
     pub left: bool,
     pub right: bool,
@@ -13,6 +28,12 @@ pub struct Joypad {
     pub select_buttons: bool,
 }
 
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
@@ -111,4 +132,35 @@ impl Joypad {
 
         request_interrupt
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for pressed in [
+            self.right,
+            self.left,
+            self.up,
+            self.down,
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+        ] {
+            w.bool(pressed);
+        }
+        w.bool(self.select_dpad);
+        w.bool(self.select_buttons);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.right = r.bool()?;
+        self.left = r.bool()?;
+        self.up = r.bool()?;
+        self.down = r.bool()?;
+        self.a = r.bool()?;
+        self.b = r.bool()?;
+        self.select = r.bool()?;
+        self.start = r.bool()?;
+        self.select_dpad = r.bool()?;
+        self.select_buttons = r.bool()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,25 @@
+pub mod apu;
+pub mod archive;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod dma;
+pub mod gameboy;
+pub mod interrupts;
+pub mod joypad;
+pub mod licensee;
+pub mod memory_bus;
+pub mod patch;
+pub mod ppu;
+pub mod rtc;
+pub mod savestate;
+pub mod screenshot;
+pub mod serial;
+pub mod timer;
+
+pub use cartridge::{Cartridge, CartridgeError};
+pub use cpu::Lockup;
+pub use gameboy::{GameBoy, CPU_CLOCK_HZ, CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use joypad::Button;
+pub use memory_bus::MemoryBus;
+pub use ppu::Renderer;
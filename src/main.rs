@@ -1,41 +1,147 @@
+#[cfg(feature = "audio")]
+mod audio_output;
+mod frame_pacer;
+mod speed_overlay;
+
 use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use frame_pacer::FramePacer;
+use gameboy_emulator::cartridge::{self, CartridgeHeader, CgbSupport, Destination, MbcType};
+use gameboy_emulator::{
+    licensee, Button, Cartridge, GameBoy, Lockup, Renderer, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
 
-mod bus;
-mod cartridge;
-mod cpu;
-mod dma;
-mod interrupts;
-mod joypad;
-mod ppu;
-mod timer;
+// Flush battery RAM to disk roughly every 5 seconds while running
+const SAVE_INTERVAL_FRAMES: u32 = 300;
 
-use bus::Bus;
-use cartridge::Cartridge;
-use cpu::Cpu;
+// Number keys pick the save state slot, F5 saves to it and F8 loads from it
+const SLOT_KEYS: [Key; 9] = [
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+];
 
-const WIDTH: usize = 160;
-const HEIGHT: usize = 144;
+// Accuracy/enhancement switches shared by the window and headless modes
+struct EmulationOptions {
+    sprite_limit: bool,
+    renderer: Renderer,
+    access_blocking: bool,
+}
+
+impl EmulationOptions {
+    fn apply(&self, gameboy: &mut GameBoy) {
+        gameboy.set_sprite_limit(self.sprite_limit);
+        gameboy.set_renderer(self.renderer);
+        gameboy.set_access_blocking(self.access_blocking);
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <path_to_rom.gb>", args[0]);
+    let mut rom_path = None;
+    let mut patch_path = None;
+    let mut show_info = false;
+    let mut headless = false;
+    let mut show_speed = false;
+    let mut frames = None;
+    let mut screenshot_path = None;
+    let mut options = EmulationOptions {
+        sprite_limit: true,
+        renderer: Renderer::Scanline,
+        access_blocking: true,
+    };
+    let mut arg_iter = args[1..].iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--info" => show_info = true,
+            "--patch" => patch_path = arg_iter.next().cloned(),
+            "--headless" => headless = true,
+            "--show-speed" => show_speed = true,
+            "--frames" => frames = arg_iter.next().and_then(|n| n.parse::<u32>().ok()),
+            "--screenshot" => screenshot_path = arg_iter.next().cloned(),
+            "--no-sprite-limit" => options.sprite_limit = false,
+            "--no-access-blocking" => options.access_blocking = false,
+            "--renderer" => match arg_iter.next().map(String::as_str) {
+                Some("scanline") => options.renderer = Renderer::Scanline,
+                Some("fifo") => options.renderer = Renderer::Fifo,
+                _ => {
+                    eprintln!("--renderer must be 'scanline' or 'fifo'");
+                    std::process::exit(1);
+                }
+            },
+            _ => rom_path = Some(arg.clone()),
+        }
+    }
+    let Some(rom_path) = rom_path else {
+        eprintln!(
+            "Usage: {} [--info] [--patch <file.ips|ups|bps>] [--no-sprite-limit] [--renderer scanline|fifo] [--no-access-blocking] [--show-speed] [--headless --frames <n> [--screenshot <file.png>]] <path_to_rom.gb>",
+            args[0]
+        );
         std::process::exit(1);
+    };
+
+    if show_info {
+        // Straight from the file: the loader would refuse the very dumps that
+        // need triaging (unknown mappers, short files)
+        let rom =
+            Cartridge::read_image(&rom_path, patch_path.as_deref()).and_then(|(rom, patch)| {
+                CartridgeHeader::parse(&rom).map(|header| (header, rom, patch))
+            });
+        match rom {
+            Ok((header, rom, patch)) => print_info(&header, &rom, patch.as_deref()),
+            Err(e) => {
+                eprintln!("Failed to read ROM: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
     }
-    let rom_path = &args[1];
 
-    let cart = Cartridge::new(rom_path).unwrap_or_else(|e| {
+    let cart = Cartridge::with_patch(&rom_path, patch_path.as_deref()).unwrap_or_else(|e| {
         eprintln!("Failed to load ROM: {}", e);
         std::process::exit(1);
     });
+    if let Some(patch) = &cart.patch_path {
+        eprintln!("Applied patch {}", patch.display());
+    }
+    if let Some(mismatch) = &cart.save_size_mismatch {
+        eprintln!("Warning: {}", mismatch);
+    }
+    if headless {
+        let Some(frames) = frames else {
+            eprintln!("--headless needs --frames <n>");
+            std::process::exit(1);
+        };
+        // game.gb -> game.png unless told otherwise
+        let screenshot_path = screenshot_path
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&rom_path).with_extension("png"));
+        run_headless(cart, frames, &screenshot_path, &options);
+        return;
+    }
+    if let Err(e) = cart.check_header() {
+        eprintln!(
+            "Warning: {}, real hardware would refuse to boot this ROM",
+            e
+        );
+    }
 
     let title = cart.header.title.clone();
-    let bus = Bus::new(cart);
+    let mut gameboy = GameBoy::new(cart);
+    options.apply(&mut gameboy);
     let mut window = Window::new(
         &title,
-        WIDTH,
-        HEIGHT,
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
         WindowOptions {
             scale: Scale::X4,
             ..WindowOptions::default()
@@ -45,43 +151,279 @@ fn main() {
         panic!("{}", e);
     });
 
-    window.set_target_fps(60);
+    // FramePacer does the pacing; don't let minifb sleep on top of it
+    window.set_target_fps(0);
 
-    let mut cpu = Cpu::new(bus);
+    #[cfg(feature = "audio")]
+    let audio = match audio_output::AudioOutput::new() {
+        Ok(audio) => {
+            gameboy.set_sample_rate(audio.sample_rate());
+            Some(audio)
+        }
+        Err(e) => {
+            eprintln!("Audio disabled: {}", e);
+            None
+        }
+    };
 
-    const CYCLES_PER_FRAME: u32 = 70224;
+    let mut frames_since_save = 0;
+    let mut rumble = false;
+    let mut locked = false;
+    let mut speed = 100;
+    let mut slot = 1;
+    let mut pacer = FramePacer::new();
+    let mut overlay_frame = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
     while window.is_open() && !window.is_key_down(Key::Escape) {
+        if window.is_key_pressed(Key::F1, KeyRepeat::No) {
+            show_speed = !show_speed;
+        }
+        for (i, key) in SLOT_KEYS.iter().enumerate() {
+            if window.is_key_pressed(*key, KeyRepeat::No) {
+                slot = i + 1;
+                eprintln!("Save state slot {}", slot);
+            }
+        }
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            let path = state_path(&rom_path, slot);
+            match fs::write(&path, gameboy.save_state()) {
+                Ok(()) => eprintln!("Saved state to slot {}", slot),
+                Err(e) => eprintln!("Failed to write {}: {}", path.display(), e),
+            }
+        }
+        if window.is_key_pressed(Key::F8, KeyRepeat::No) {
+            let path = state_path(&rom_path, slot);
+            match fs::read(&path) {
+                Ok(data) => match gameboy.load_state(&data) {
+                    Ok(()) => eprintln!("Loaded state from slot {}", slot),
+                    Err(e) => eprintln!("Failed to load slot {}: {}", slot, e),
+                },
+                Err(e) => eprintln!("Failed to read {}: {}", path.display(), e),
+            }
+        }
+
         let inputs = [
-            (7, window.is_key_pressed(Key::Enter, KeyRepeat::No)),
-            (6, window.is_key_pressed(Key::Space, KeyRepeat::No)),
-            (5, window.is_key_down(Key::Z)),
-            (4, window.is_key_down(Key::X)),
-            (3, window.is_key_down(Key::Down)),
-            (2, window.is_key_down(Key::Up)),
-            (1, window.is_key_down(Key::Left)),
-            (0, window.is_key_down(Key::Right)),
+            (
+                Button::Start,
+                window.is_key_pressed(Key::Enter, KeyRepeat::No),
+            ),
+            (
+                Button::Select,
+                window.is_key_pressed(Key::Space, KeyRepeat::No),
+            ),
+            (Button::B, window.is_key_down(Key::Z)),
+            (Button::A, window.is_key_down(Key::X)),
+            (Button::Down, window.is_key_down(Key::Down)),
+            (Button::Up, window.is_key_down(Key::Up)),
+            (Button::Left, window.is_key_down(Key::Left)),
+            (Button::Right, window.is_key_down(Key::Right)),
         ];
 
-        for (bit, pressed) in inputs {
-            let request_int = cpu.bus.joypad.set_button(bit, pressed);
-            if request_int {
-                cpu.bus.request_interrupt(interrupts::Interrupt::Joypad);
-            }
+        for (button, pressed) in inputs {
+            gameboy.set_button(button, pressed);
         }
 
-        let mut cycles_this_frame = 0;
+        gameboy.run_frame();
 
-        while cycles_this_frame < CYCLES_PER_FRAME {
-            let cycles = cpu.step() as u32;
-            cycles_this_frame += cycles;
+        #[cfg(feature = "audio")]
+        if let Some(audio) = &audio {
+            audio.push(&gameboy.take_samples());
+        }
 
-            cpu.bus.tick(cycles as u8);
+        if pacer.should_present() {
+            let frame = if show_speed {
+                overlay_frame.copy_from_slice(gameboy.framebuffer());
+                speed_overlay::draw_speed(&mut overlay_frame, SCREEN_WIDTH, speed);
+                &overlay_frame
+            } else {
+                gameboy.framebuffer()
+            };
+            window
+                .update_with_buffer(frame, SCREEN_WIDTH, SCREEN_HEIGHT)
+                .unwrap();
+        } else {
+            // Skipped frames still have to pump events, or input goes stale
+            window.update();
+        }
 
-            cpu.check_interrupts();
+        let mut title_changed = false;
+        if let Some(measured) = pacer.wait() {
+            title_changed = measured != speed;
+            speed = measured;
+        }
+        // minifb can't drive a motor, so show rumble in the title bar instead
+        if gameboy.rumble_active() != rumble {
+            rumble = gameboy.rumble_active();
+            title_changed = true;
+        }
+        // Loading a state can also clear a lockup
+        if gameboy.lockup().is_some() != locked {
+            locked = !locked;
+            title_changed = true;
+            if let Some(lockup) = gameboy.lockup() {
+                report_lockup(lockup);
+            }
+        }
+        if title_changed {
+            window.set_title(&window_title(&title, speed, rumble, locked));
         }
 
-        window
-            .update_with_buffer(&cpu.bus.ppu.buffer, WIDTH, HEIGHT)
-            .unwrap();
+        frames_since_save += 1;
+        if frames_since_save >= SAVE_INTERVAL_FRAMES {
+            frames_since_save = 0;
+            if let Err(e) = gameboy.save_ram() {
+                eprintln!("Failed to write save file: {}", e);
+            }
+        }
+    }
+
+    if let Err(e) = gameboy.save_ram() {
+        eprintln!("Failed to write save file: {}", e);
+    }
+}
+
+fn window_title(title: &str, speed: u32, rumble: bool, locked: bool) -> String {
+    let mut text = format!("{} - {}%", title, speed);
+    if rumble {
+        text.push_str(" [RUMBLE]");
+    }
+    if locked {
+        text.push_str(" [CPU LOCKED]");
+    }
+    text
+}
+
+fn report_lockup(lockup: Lockup) {
+    eprintln!(
+        "CPU locked up: illegal opcode {:#04X} at {:#06X}",
+        lockup.opcode, lockup.address
+    );
+}
+
+// Runs a fixed number of frames as fast as possible with no window, audio or
+// input, then dumps the final frame. Battery RAM is never written back and
+// the MBC3 clock runs on emulated time, so repeated runs give the same result.
+fn run_headless(
+    mut cart: Cartridge,
+    frames: u32,
+    screenshot_path: &Path,
+    options: &EmulationOptions,
+) {
+    cart.use_emulated_rtc();
+    let mut gameboy = GameBoy::new(cart);
+    options.apply(&mut gameboy);
+    for _ in 0..frames {
+        gameboy.run_frame();
+    }
+
+    // Test ROMs report their results over the link port
+    let serial = gameboy.take_serial_output();
+    if !serial.is_empty() {
+        eprintln!("{}", String::from_utf8_lossy(&serial));
+    }
+    if let Some(lockup) = gameboy.lockup() {
+        report_lockup(lockup);
+    }
+
+    if let Err(e) = fs::write(screenshot_path, gameboy.screenshot_png()) {
+        eprintln!("Failed to write {}: {}", screenshot_path.display(), e);
+        std::process::exit(1);
+    }
+    println!("{:08x}", gameboy.framebuffer_hash());
+}
+
+// game.gb (or game.gb.gz, game.zip) -> game.ss1, game.ss2, ...
+fn state_path(rom_path: &str, slot: usize) -> PathBuf {
+    cartridge::strip_gzip_extension(Path::new(rom_path)).with_extension(format!("ss{}", slot))
+}
+
+// Dumps everything the cartridge header tells us, for triaging ROM dumps
+fn print_info(header: &CartridgeHeader, rom: &[u8], patch: Option<&Path>) {
+    let ok = |valid: bool| if valid { "OK" } else { "BAD" };
+
+    let mapper = match header.cart_type {
+        MbcType::RomOnly => "ROM only".to_string(),
+        MbcType::MBC1 => "MBC1".to_string(),
+        MbcType::MBC2 => "MBC2".to_string(),
+        MbcType::MBC3 => "MBC3".to_string(),
+        MbcType::MBC5 => "MBC5".to_string(),
+        MbcType::Unknown(code) => format!("Unsupported ({:#04X})", code),
+    };
+    let mut features = Vec::new();
+    if header.has_battery {
+        features.push("battery");
+    }
+    if header.has_rtc {
+        features.push("RTC");
+    }
+    if header.has_rumble {
+        features.push("rumble");
+    }
+
+    println!("Title:            {}", header.title);
+    println!(
+        "Manufacturer:     {}",
+        header.manufacturer_code.as_deref().unwrap_or("-")
+    );
+    println!(
+        "Licensee:         {} ({})",
+        header.licensee(),
+        if header.old_licensee_code == licensee::USE_NEW_LICENSEE {
+            header.new_licensee_code.clone()
+        } else {
+            format!("{:#04X}", header.old_licensee_code)
+        }
+    );
+    println!(
+        "Cartridge type:   {:#04X} {} {}",
+        header.cart_type_code,
+        mapper,
+        if features.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", features.join(", "))
+        }
+    );
+    println!(
+        "ROM size:         {} KiB (file: {} KiB)",
+        header.rom_size / 1024,
+        rom.len() / 1024
+    );
+    println!("RAM size:         {} KiB", header.ram_size / 1024);
+    println!(
+        "CGB support:      {}",
+        match header.cgb_support {
+            CgbSupport::DmgOnly => "no",
+            CgbSupport::Enhanced => "enhanced",
+            CgbSupport::CgbOnly => "CGB only",
+        }
+    );
+    println!(
+        "SGB support:      {}",
+        if header.sgb_support { "yes" } else { "no" }
+    );
+    println!(
+        "Destination:      {}",
+        match header.destination {
+            Destination::Japan => "Japan",
+            Destination::Overseas => "Overseas",
+        }
+    );
+    println!("Version:          {}", header.version);
+    if let Some(patch) = patch {
+        println!("Patch:            {}", patch.display());
     }
+    println!(
+        "Nintendo logo:    {}",
+        ok(cartridge::has_nintendo_logo(rom))
+    );
+    println!(
+        "Header checksum:  {:#04X} {}",
+        header.check_sum,
+        ok(cartridge::header_checksum(rom) == header.check_sum)
+    );
+    println!(
+        "Global checksum:  {:#06X} {}",
+        header.global_check_sum,
+        ok(cartridge::global_checksum(rom) == header.global_check_sum)
+    );
 }
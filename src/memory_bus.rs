@@ -0,0 +1,21 @@
+// What the CPU sees of the rest of the machine. `Bus` is the real one; tests,
+// tracing wrappers or other system buses can stand in for it.
+use crate::interrupts::Interrupt;
+
+pub trait MemoryBus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // Advances everything that isn't the CPU by one M-cycle (4 clock cycles)
+    fn tick(&mut self);
+    // Interrupts that are both requested (IF) and enabled (IE), as IF bits
+    fn pending_interrupts(&self) -> u8;
+    // Clears the request once the CPU starts servicing it
+    fn acknowledge_interrupt(&mut self, interrupt: Interrupt);
+    // STOP: DIV is reset and the LCD goes blank while the clock is stopped.
+    // Buses without a system clock have nothing to do.
+    fn stop(&mut self) {}
+    // True while a selected joypad line is held low, which ends STOP
+    fn joypad_input(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,472 @@
+// Soft-patching of ROM images in memory (translations, ROM hacks).
+// https://zerosoft.zophar.net/ips.php
+// https://www.romhacking.net/documents/392/ (UPS)
+// https://www.romhacking.net/documents/746/ (BPS)
+use std::fmt;
+
+use crate::cartridge::MAX_ROM_SIZE;
+
+// A patch's own CRC only covers its bytes, so sizes it declares can't be
+// trusted beyond what a cartridge can hold
+const MAX_TARGET_SIZE: usize = MAX_ROM_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatchError {
+    UnknownFormat,
+    // Truncated patch or a record pointing outside the image
+    Malformed,
+    // The patched ROM would be bigger than any cartridge
+    TooLarge,
+    SourceChecksum,
+    TargetChecksum,
+    PatchChecksum,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Malformed => write!(f, "patch is corrupt or truncated"),
+            PatchError::TooLarge => write!(f, "patched ROM would be larger than 8 MiB"),
+            PatchError::SourceChecksum => write!(f, "patch was made for a different ROM"),
+            PatchError::TargetChecksum => write!(f, "patched ROM failed its checksum"),
+            PatchError::PatchChecksum => write!(f, "patch file failed its checksum"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+// File extensions looked for next to a ROM, in order of preference
+pub const PATCH_EXTENSIONS: [&str; 3] = ["bps", "ups", "ips"];
+
+// Applies `patch` to `rom`, picking the format from the magic bytes
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// Cursor over the patch bytes that turns running off the end into an error
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        let byte = *self.data.get(self.pos).ok_or(PatchError::Malformed)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.pos.checked_add(len).ok_or(PatchError::Malformed)?;
+        let bytes = self.data.get(self.pos..end).ok_or(PatchError::Malformed)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16_be(&mut self) -> Result<usize, PatchError> {
+        let b = self.bytes(2)?;
+        Ok(((b[0] as usize) << 8) | b[1] as usize)
+    }
+
+    fn u24_be(&mut self) -> Result<usize, PatchError> {
+        let b = self.bytes(3)?;
+        Ok(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+
+    // Variable-length integer shared by UPS and BPS
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            value = value
+                .checked_add((byte & 0x7F) as usize * shift)
+                .ok_or(PatchError::Malformed)?;
+            if (byte & 0x80) != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or(PatchError::Malformed)?;
+            value = value.checked_add(shift).ok_or(PatchError::Malformed)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(patch, 5);
+
+    loop {
+        let offset = reader.u24_be()?;
+        // "EOF" marks the end of the records
+        if offset == 0x454F46 {
+            break;
+        }
+
+        let size = reader.u16_be()?;
+        if size == 0 {
+            // RLE record: one byte repeated
+            let count = reader.u16_be()?;
+            let value = reader.u8()?;
+            if offset + count > MAX_TARGET_SIZE {
+                return Err(PatchError::TooLarge);
+            }
+            if out.len() < offset + count {
+                out.resize(offset + count, 0);
+            }
+            out[offset..offset + count].fill(value);
+        } else {
+            let data = reader.bytes(size)?;
+            if offset + size > MAX_TARGET_SIZE {
+                return Err(PatchError::TooLarge);
+            }
+            if out.len() < offset + size {
+                out.resize(offset + size, 0);
+            }
+            out[offset..offset + size].copy_from_slice(data);
+        }
+    }
+
+    // Optional truncation extension after EOF
+    if let Ok(size) = reader.u24_be() {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+// Splits off the 12-byte footer (source, target and patch CRC32) and checks the
+// patch's own checksum and the source checksum.
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<(usize, u32), PatchError> {
+    if patch.len() < 16 {
+        return Err(PatchError::Malformed);
+    }
+    let footer = patch.len() - 12;
+    let crc = |i: usize| u32::from_le_bytes(patch[i..i + 4].try_into().unwrap());
+
+    if crc32fast::hash(&patch[..patch.len() - 4]) != crc(footer + 8) {
+        return Err(PatchError::PatchChecksum);
+    }
+    if crc32fast::hash(rom) != crc(footer) {
+        return Err(PatchError::SourceChecksum);
+    }
+    Ok((footer, crc(footer + 4)))
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (footer, target_crc) = check_footer(rom, patch)?;
+    let mut reader = Reader::new(&patch[..footer], 4);
+
+    let _source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::TooLarge);
+    }
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+
+    // Each hunk skips ahead, then XORs bytes in until a 0x00 terminator
+    let mut pos: usize = 0;
+    while reader.pos < footer {
+        pos = pos
+            .checked_add(reader.varint()?)
+            .ok_or(PatchError::Malformed)?;
+        loop {
+            let value = reader.u8()?;
+            if value == 0 {
+                pos = pos.checked_add(1).ok_or(PatchError::Malformed)?;
+                break;
+            }
+            if pos < out.len() {
+                out[pos] ^= value;
+            }
+            pos = pos.checked_add(1).ok_or(PatchError::Malformed)?;
+        }
+    }
+
+    if crc32fast::hash(&out) != target_crc {
+        return Err(PatchError::TargetChecksum);
+    }
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (footer, target_crc) = check_footer(rom, patch)?;
+    let mut reader = Reader::new(&patch[..footer], 4);
+
+    let _source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::TooLarge);
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    // Relative offsets are stored as magnitude << 1 | sign
+    let seek = |offset: usize, encoded: usize| -> Result<usize, PatchError> {
+        let delta = encoded >> 1;
+        if (encoded & 1) != 0 {
+            offset.checked_sub(delta).ok_or(PatchError::Malformed)
+        } else {
+            offset.checked_add(delta).ok_or(PatchError::Malformed)
+        }
+    };
+
+    while reader.pos < footer {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        // Checked up front so no action can grow the output past the target
+        if length > target_size - out.len() {
+            return Err(PatchError::Malformed);
+        }
+
+        match data & 0x03 {
+            // SourceRead: copy from the same position in the source
+            0 => {
+                let start = out.len();
+                let bytes = rom
+                    .get(start..start + length)
+                    .ok_or(PatchError::Malformed)?;
+                out.extend_from_slice(bytes);
+            }
+            // TargetRead: literal bytes from the patch
+            1 => {
+                let bytes = reader.bytes(length)?;
+                out.extend_from_slice(bytes);
+            }
+            // SourceCopy: copy from anywhere in the source
+            2 => {
+                source_offset = seek(source_offset, reader.varint()?)?;
+                let end = source_offset
+                    .checked_add(length)
+                    .ok_or(PatchError::Malformed)?;
+                let bytes = rom.get(source_offset..end).ok_or(PatchError::Malformed)?;
+                out.extend_from_slice(bytes);
+                source_offset += length;
+            }
+            // TargetCopy: copy from output already written; may overlap itself
+            3 => {
+                target_offset = seek(target_offset, reader.varint()?)?;
+                for _ in 0..length {
+                    let byte = *out.get(target_offset).ok_or(PatchError::Malformed)?;
+                    out.push(byte);
+                    target_offset = target_offset.checked_add(1).ok_or(PatchError::Malformed)?;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if out.len() != target_size || crc32fast::hash(&out) != target_crc {
+        return Err(PatchError::TargetChecksum);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let bits = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | bits);
+                return;
+            }
+            out.push(bits);
+            value -= 1;
+        }
+    }
+
+    // Appends the source, target and patch CRC32s
+    fn footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn ips_patch() -> Vec<u8> {
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at offset 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE: 3 x 0xCC at offset 7, growing the image
+        patch.extend_from_slice(&[0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        patch
+    }
+
+    #[test]
+    fn ips_records_and_rle() {
+        let out = apply(&ROM, &ips_patch()).unwrap();
+        assert_eq!(out, [1, 0xAA, 0xBB, 4, 5, 6, 7, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn ips_truncation_extension() {
+        let mut patch = ips_patch();
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply(&ROM, &patch).unwrap(), [1, 0xAA, 0xBB, 4]);
+    }
+
+    #[test]
+    fn ips_truncated_patch() {
+        let patch = ips_patch();
+        assert_eq!(
+            apply(&ROM, &patch[..patch.len() - 6]),
+            Err(PatchError::Malformed)
+        );
+    }
+
+    #[test]
+    fn ips_record_past_max_size() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x00]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::TooLarge));
+    }
+
+    // [1, 2, 9, 4, 5, 6, 7, 8, 0xAA]: one byte changed, one appended
+    fn ups_patch(target_size: usize) -> Vec<u8> {
+        let target = [1, 2, 9, 4, 5, 6, 7, 8, 0xAA];
+        let mut patch = b"UPS1".to_vec();
+        varint(&mut patch, ROM.len());
+        varint(&mut patch, target_size);
+        varint(&mut patch, 2);
+        patch.extend_from_slice(&[3 ^ 9, 0]);
+        varint(&mut patch, 4);
+        patch.extend_from_slice(&[0xAA, 0]);
+        footer(patch, &ROM, &target)
+    }
+
+    #[test]
+    fn ups_round_trip() {
+        let out = apply(&ROM, &ups_patch(9)).unwrap();
+        assert_eq!(out, [1, 2, 9, 4, 5, 6, 7, 8, 0xAA]);
+    }
+
+    #[test]
+    fn ups_checksums() {
+        let mut patch = ups_patch(9);
+        assert_eq!(apply(&[0; 8], &patch), Err(PatchError::SourceChecksum));
+        patch[6] ^= 0xFF;
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::PatchChecksum));
+    }
+
+    #[test]
+    fn ups_truncated_patch() {
+        assert_eq!(apply(&ROM, b"UPS1\x88"), Err(PatchError::Malformed));
+    }
+
+    #[test]
+    fn ups_oversized_target() {
+        // Passes its own CRC but claims a target no cartridge could hold
+        let patch = ups_patch(usize::MAX / 2);
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::TooLarge));
+    }
+
+    #[test]
+    fn ups_skip_overflow() {
+        let target = ROM;
+        let mut patch = b"UPS1".to_vec();
+        varint(&mut patch, ROM.len());
+        varint(&mut patch, ROM.len());
+        for _ in 0..2 {
+            varint(&mut patch, usize::MAX / 2 + 1);
+            patch.push(0);
+        }
+        let patch = footer(patch, &ROM, &target);
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::Malformed));
+    }
+
+    const BPS_TARGET: [u8; 11] = [1, 2, 3, 4, 0xEE, 0xFF, 5, 6, 0xEE, 0xFF, 5];
+
+    fn bps_patch(target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        varint(&mut patch, ROM.len());
+        varint(&mut patch, target.len());
+        varint(&mut patch, 0);
+        // SourceRead 4
+        varint(&mut patch, 3 << 2);
+        // TargetRead 2
+        varint(&mut patch, (1 << 2) | 1);
+        patch.extend_from_slice(&[0xEE, 0xFF]);
+        // SourceCopy 2 from source offset +4
+        varint(&mut patch, (1 << 2) | 2);
+        varint(&mut patch, 4 << 1);
+        // TargetCopy 3 from target offset +4
+        varint(&mut patch, (2 << 2) | 3);
+        varint(&mut patch, 4 << 1);
+        footer(patch, &ROM, target)
+    }
+
+    #[test]
+    fn bps_round_trip() {
+        assert_eq!(apply(&ROM, &bps_patch(&BPS_TARGET)).unwrap(), BPS_TARGET);
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let mut patch = bps_patch(&BPS_TARGET);
+        assert_eq!(apply(&[0; 8], &patch), Err(PatchError::SourceChecksum));
+        patch[8] ^= 0x01;
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::PatchChecksum));
+
+        // Valid patch, but the target CRC doesn't match what it produces
+        let mut wrong = BPS_TARGET;
+        wrong[0] = 0;
+        let patch = footer(
+            bps_patch(&BPS_TARGET)[..bps_patch(&BPS_TARGET).len() - 12].to_vec(),
+            &ROM,
+            &wrong,
+        );
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::TargetChecksum));
+    }
+
+    #[test]
+    fn bps_truncated_patch() {
+        assert_eq!(apply(&ROM, b"BPS1\x88\x8B"), Err(PatchError::Malformed));
+    }
+
+    #[test]
+    fn bps_action_past_target_size() {
+        // The actions write 11 bytes into a 4 byte target
+        let mut patch = bps_patch(&BPS_TARGET);
+        patch[5] = 0x84;
+        let body = patch[..patch.len() - 12].to_vec();
+        let patch = footer(body, &ROM, &BPS_TARGET[..4]);
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::Malformed));
+    }
+
+    #[test]
+    fn bps_oversized_target() {
+        let mut patch = b"BPS1".to_vec();
+        varint(&mut patch, ROM.len());
+        varint(&mut patch, MAX_TARGET_SIZE + 1);
+        varint(&mut patch, 0);
+        let patch = footer(patch, &ROM, &[]);
+        assert_eq!(apply(&ROM, &patch), Err(PatchError::TooLarge));
+    }
+}
@@ -1,3 +1,11 @@
+mod fifo;
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use fifo::PixelFifo;
+
+// Hardware only fetches this many sprites per scanline
+const MAX_SPRITES_PER_LINE: usize = 10;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PpuMode {
     HBlank = 0,
@@ -6,6 +14,26 @@ pub enum PpuMode {
     PixelTransfer = 3,
 }
 
+impl PpuMode {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamSearch,
+            _ => PpuMode::PixelTransfer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+    // Whole line drawn at once when mode 3 ends; fast, fixed 172-dot mode 3
+    Scanline,
+    // Dot-by-dot fetcher and pixel FIFO; honours mid-line register writes and
+    // variable mode 3 length
+    Fifo,
+}
+
 pub struct Ppu {
     pub vram: [u8; 8192],
     pub oam: [u8; 160],
@@ -26,6 +54,29 @@ pub struct Ppu {
     pub mode: PpuMode,
     pub cycle_accumulator: u32,
     pub stat_line: bool,
+    // Enhancement: draw every sprite on a line instead of the first 10
+    pub unlimited_sprites: bool,
+    pub renderer: Renderer,
+    // Lock VRAM/OAM from the CPU during the modes that use them; can be
+    // turned off for debugging
+    pub access_blocking: bool,
+    fifo: PixelFifo,
+
+    // Window state: https://gbdev.io/pandocs/Scrolling.html#window
+    // Internal line counter, only advanced on lines where the window was drawn
+    window_line: u8,
+    // Latched once LY == WY during a frame
+    window_y_triggered: bool,
+    // WX=166 quirk: the window fills the whole of the following line
+    window_wrap: bool,
+    // Raw BG/window color IDs (before BGP) of the line being drawn, for OBJ priority
+    bg_line: [u8; 160],
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ppu {
@@ -50,11 +101,25 @@ impl Ppu {
             mode: PpuMode::OamSearch,
             cycle_accumulator: 0,
             stat_line: false,
+            unlimited_sprites: false,
+            renderer: Renderer::Scanline,
+            access_blocking: true,
+            fifo: PixelFifo::new(),
+
+            window_line: 0,
+            window_y_triggered: false,
+            window_wrap: false,
+            bg_line: [0; 160],
         }
     }
 
+    // CPU-side access. VRAM is locked while the PPU draws and OAM while it
+    // scans or draws; locked reads return 0xFF and writes are dropped.
+    // https://gbdev.io/pandocs/Accessing_VRAM_and_OAM.html
     pub fn read(&self, address: u16) -> u8 {
         match address {
+            0x8000..=0x9FFF if self.vram_locked() => 0xFF,
+            0xFE00..=0xFE9F if self.oam_locked() => 0xFF,
             0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize],
             0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize],
 
@@ -82,6 +147,8 @@ impl Ppu {
 
     pub fn write(&mut self, address: u16, value: u8) {
         match address {
+            0x8000..=0x9FFF if self.vram_locked() => {}
+            0xFE00..=0xFE9F if self.oam_locked() => {}
             0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize] = value,
             0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize] = value,
             0xFF40 => {
@@ -90,6 +157,8 @@ impl Ppu {
                     self.ly = 0;
                     self.mode = PpuMode::HBlank;
                     self.cycle_accumulator = 0;
+                    self.reset_window();
+                    self.fifo = PixelFifo::new();
                 }
             }
             0xFF41 => self.stat = (self.stat & 0xFC) | (value & 0xF8),
@@ -114,6 +183,9 @@ impl Ppu {
         if !self.is_lcd_enabled() {
             return (false, false);
         }
+        if self.renderer == Renderer::Fifo {
+            return self.tick_fifo(cycles);
+        }
 
         self.cycle_accumulator += cycles as u32;
 
@@ -126,6 +198,7 @@ impl Ppu {
                 if self.ly >= 154 {
                     self.ly = 0;
                     self.mode = PpuMode::OamSearch;
+                    self.reset_window();
                 }
             }
         } else {
@@ -151,17 +224,23 @@ impl Ppu {
             }
         }
 
+        if self.update_stat_line() {
+            stat_irq = true;
+        }
+
+        (vblank_irq, stat_irq)
+    }
+
+    // STAT interrupts fire on the rising edge of the OR of all enabled sources
+    fn update_stat_line(&mut self) -> bool {
         let stat_signal = (self.ly == self.lyc && (self.stat & 0x40) != 0)
             || (self.mode == PpuMode::OamSearch && (self.stat & 0x20) != 0)
             || (self.mode == PpuMode::VBlank && (self.stat & 0x10) != 0)
             || (self.mode == PpuMode::HBlank && (self.stat & 0x08) != 0);
 
-        if stat_signal && !self.stat_line {
-            stat_irq = true;
-        }
+        let rising = stat_signal && !self.stat_line;
         self.stat_line = stat_signal;
-
-        (vblank_irq, stat_irq)
+        rising
     }
     //https://gbdev.io/pandocs/pixel_fifo.html
     pub fn draw_scanline(&mut self) {
@@ -172,36 +251,99 @@ impl Ppu {
             }
             return;
         }
+        // WY is compared against LY at the start of every line, so a WY change
+        // mid-frame only takes effect once LY reaches the new value
+        if self.ly == self.wy {
+            self.window_y_triggered = true;
+        }
+
+        // Screen column the window starts at, if it's visible on this line.
+        // WX < 7 pushes the window's leftmost pixels off screen. On DMG, LCDC.0
+        // turns off the window as well as the background.
+        let window_start =
+            if !self.bg_window_enabled() || !self.is_window_enabled() || !self.window_y_triggered {
+                None
+            } else if self.window_wrap {
+                Some(0)
+            } else if self.wx <= 166 {
+                Some(self.wx as i16 - 7)
+            } else {
+                None
+            };
+        self.window_wrap = window_start.is_some() && self.wx == 166;
+
+        let bg_enabled = self.bg_window_enabled();
         let bg_y = self.scy.wrapping_add(self.ly);
-        let base_map_area = self.bg_tile_map_area();
-        let tile_row = (bg_y / 8) as u16;
-        let internal_y = (bg_y % 8) as u16;
         let canvas_y = self.ly as usize;
-        for x in 0..160 {
-            let bg_x = self.scx.wrapping_add(x);
-            let tile_col = (bg_x / 8) as u16;
-            let internal_x = 7 - (bg_x % 8);
-            let map_address = base_map_area + (tile_row * 32) + tile_col;
-            let tile_index = self.read(map_address);
-            let tile_data_address = match self.tile_data_area() {
-                0x8000 => 0x8000 + (tile_index as u16 * 16),
-                0x8800 => 0x9000 + (tile_index as i8 as i16 * 16) as u16,
+        for x in 0..160u8 {
+            // With LCDC.0 clear the whole line is blank (color 0)
+            let color_id = match window_start {
+                _ if !bg_enabled => 0,
+                Some(start) if x as i16 >= start => {
+                    let window_x = (x as i16 - start) as u8;
+                    self.tile_pixel(self.window_tile_map_area(), window_x, self.window_line)
+                }
                 _ => {
-                    unreachable!()
+                    let bg_x = self.scx.wrapping_add(x);
+                    self.tile_pixel(self.bg_tile_map_area(), bg_x, bg_y)
                 }
             };
-            let address = tile_data_address + (internal_y * 2);
-            let byte1 = self.read(address);
-            let byte2 = self.read(address + 1);
-            let bit_low = (byte1 >> internal_x) & 1;
-            let bit_high = (byte2 >> internal_x) & 1;
-            let color_id = (bit_high << 1) | bit_low;
+            self.bg_line[x as usize] = color_id;
             let palette_color = self.get_color(color_id, self.bgp);
             self.buffer[canvas_y * 160 + x as usize] = palette_color;
         }
+        if window_start.is_some_and(|start| start < 160) {
+            self.window_line += 1;
+        }
         self.draw_sprites();
     }
 
+    // Color index of pixel (x, y) of the 256x256 tile map at `map_area`
+    fn tile_pixel(&self, map_area: u16, x: u8, y: u8) -> u8 {
+        let tile_row = (y / 8) as u16;
+        let tile_col = (x / 8) as u16;
+        let internal_y = (y % 8) as u16;
+        let internal_x = 7 - (x % 8);
+        let map_address = map_area + (tile_row * 32) + tile_col;
+        let tile_index = self.vram_read(map_address);
+        let address = self.tile_data_address(tile_index) + (internal_y * 2);
+        let byte1 = self.vram_read(address);
+        let byte2 = self.vram_read(address + 1);
+        let bit_low = (byte1 >> internal_x) & 1;
+        let bit_high = (byte2 >> internal_x) & 1;
+        (bit_high << 1) | bit_low
+    }
+
+    fn vram_locked(&self) -> bool {
+        self.access_blocking && self.mode == PpuMode::PixelTransfer
+    }
+
+    fn oam_locked(&self) -> bool {
+        self.access_blocking && matches!(self.mode, PpuMode::OamSearch | PpuMode::PixelTransfer)
+    }
+
+    // The PPU's own view of VRAM, never locked
+    fn vram_read(&self, address: u16) -> u8 {
+        self.vram[(address - 0x8000) as usize]
+    }
+
+    // Start of a BG/window tile's data, following the LCDC.4 addressing mode
+    fn tile_data_address(&self, tile_index: u8) -> u16 {
+        match self.tile_data_area() {
+            0x8000 => 0x8000 + (tile_index as u16 * 16),
+            0x8800 => 0x9000u16.wrapping_add_signed(tile_index as i8 as i16 * 16),
+            _ => {
+                unreachable!()
+            }
+        }
+    }
+
+    fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        self.window_wrap = false;
+    }
+
     fn get_color(&self, color_id: u8, palette: u8) -> u32 {
         let shade = (palette >> (color_id * 2)) & 0x03;
         match shade {
@@ -212,68 +354,152 @@ impl Ppu {
             _ => 0xFFE0F8D0,
         }
     }
+    // OAM scan: the first 10 sprites (in OAM order) that overlap the current
+    // line, regardless of their X position. Returns OAM indices.
+    fn scan_oam(&self) -> Vec<usize> {
+        let sprite_height = if self.obj_size() { 16 } else { 8 };
+        let line = self.ly as i32;
+        let limit = if self.unlimited_sprites {
+            40
+        } else {
+            MAX_SPRITES_PER_LINE
+        };
+        (0..40)
+            .filter(|i| {
+                let sprite_y = self.oam[i * 4] as i32 - 16;
+                line >= sprite_y && line < sprite_y + sprite_height
+            })
+            .take(limit)
+            .collect()
+    }
+
     fn draw_sprites(&mut self) {
         if !self.obj_enabled() {
             return;
         }
-        let sprite_height = if self.obj_size() { 16 } else { 8 };
-        let line = self.ly as i32;
-        for i in 0..40 {
-            let offset = i * 4;
-            let sprite_y = self.oam[offset] as i32 - 16;
-            let sprite_x = self.oam[offset + 1] as i32 - 8;
-            let mut tile_index = self.oam[offset + 2];
-            let flags = self.oam[offset + 3];
-            // Bit mask to extract each flag
+        // DMG priority: lower X wins, ties go to the lower OAM index
+        let mut sprites = self.scan_oam();
+        sprites.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+
+        // Pixels already claimed by a higher-priority sprite. A sprite hidden
+        // behind the BG still claims its pixels from the sprites below it.
+        let mut claimed = [false; 160];
+        for i in sprites {
+            let sprite_x = self.oam[i * 4 + 1] as i32 - 8;
+            let flags = self.oam[i * 4 + 3];
             let priority_below_bg = (flags & 0x80) != 0;
-            let y_flip = (flags & 0x40) != 0;
-            let x_flip = (flags & 0x20) != 0;
-            let pallete = (flags & 0x10) != 0;
-            if line >= sprite_y && line < (sprite_y + sprite_height) {
-                let mut row_to_draw = line - sprite_y;
-                if y_flip {
-                    row_to_draw = sprite_height - 1 - row_to_draw;
+            let palette = if (flags & 0x10) != 0 {
+                self.obp1
+            } else {
+                self.obp0
+            };
+
+            for (x, color_id) in self.sprite_pixels(i).into_iter().enumerate() {
+                let pixel_x = sprite_x + x as i32;
+                if !(0..160).contains(&pixel_x) || claimed[pixel_x as usize] {
+                    continue;
                 }
-                if sprite_height == 16 {
-                    tile_index &= 0xFE;
-                    if row_to_draw >= 8 {
-                        tile_index += 1;
-                        row_to_draw -= 8;
-                    }
+                if color_id == 0 {
+                    continue;
                 }
-                let tile_address = 0x8000 + (tile_index as u16 * 16);
-                let row_address = tile_address + (row_to_draw as u16 * 2);
-                let byte1 = self.read(row_address);
-                let byte2 = self.read(row_address + 1);
-                for x in 0..8 {
-                    let pixel_x = sprite_x + x;
-                    if pixel_x >= 0 && pixel_x < 160 {
-                        let bit_index = if x_flip { x } else { 7 - x };
-                        let bit_low = (byte1 >> bit_index) & 1;
-                        let bit_high = (byte2 >> bit_index) & 1;
-                        let color_id = (bit_high << 1) | bit_low;
-                        if color_id == 0 {
-                            continue;
-                        }
-                        let buffer_idx = (self.ly as usize * 160) + pixel_x as usize;
-                        let current_bg_pixel = self.buffer[buffer_idx];
-
-                        // Find out what the current "blank" background color is dynamically
-                        let bg_color_0 = self.get_color(0, self.bgp);
-
-                        // If BG priority is set and BG is not the blank color, hide the sprite
-                        if priority_below_bg && current_bg_pixel != bg_color_0 {
-                            continue;
-                        }
-
-                        let palette = if pallete { self.obp1 } else { self.obp0 };
-                        let color = self.get_color(color_id, palette);
-                        self.buffer[buffer_idx] = color;
-                    }
+                claimed[pixel_x as usize] = true;
+
+                // If BG priority is set, the sprite only shows over BG color 0
+                if priority_below_bg && self.bg_line[pixel_x as usize] != 0 {
+                    continue;
                 }
+
+                let buffer_idx = (self.ly as usize * 160) + pixel_x as usize;
+                self.buffer[buffer_idx] = self.get_color(color_id, palette);
             }
         }
     }
+
+    // Color IDs of OAM entry `index` on the current line, left to right with
+    // flips applied
+    fn sprite_pixels(&self, index: usize) -> [u8; 8] {
+        let offset = index * 4;
+        let sprite_height = if self.obj_size() { 16 } else { 8 };
+        let sprite_y = self.oam[offset] as i32 - 16;
+        let mut tile_index = self.oam[offset + 2];
+        let flags = self.oam[offset + 3];
+        // Bit mask to extract each flag
+        let y_flip = (flags & 0x40) != 0;
+        let x_flip = (flags & 0x20) != 0;
+
+        let mut row_to_draw = self.ly as i32 - sprite_y;
+        if y_flip {
+            row_to_draw = sprite_height - 1 - row_to_draw;
+        }
+        if sprite_height == 16 {
+            tile_index &= 0xFE;
+            if row_to_draw >= 8 {
+                tile_index += 1;
+                row_to_draw -= 8;
+            }
+        }
+        let tile_address = 0x8000 + (tile_index as u16 * 16);
+        let row_address = tile_address + (row_to_draw as u16 * 2);
+        let byte1 = self.vram_read(row_address);
+        let byte2 = self.vram_read(row_address + 1);
+
+        let mut pixels = [0; 8];
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            let bit_index = if x_flip { x } else { 7 - x };
+            let bit_low = (byte1 >> bit_index) & 1;
+            let bit_high = (byte2 >> bit_index) & 1;
+            *pixel = (bit_high << 1) | bit_low;
+        }
+        pixels
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.oam);
+        for pixel in &self.buffer {
+            w.u32(*pixel);
+        }
+        for reg in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            w.u8(reg);
+        }
+        w.u8(self.mode as u8);
+        w.u32(self.cycle_accumulator);
+        w.bool(self.stat_line);
+        w.u8(self.window_line);
+        w.bool(self.window_y_triggered);
+        w.bool(self.window_wrap);
+        self.fifo.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.bytes_into(&mut self.vram)?;
+        r.bytes_into(&mut self.oam)?;
+        for pixel in self.buffer.iter_mut() {
+            *pixel = r.u32()?;
+        }
+        self.lcdc = r.u8()?;
+        self.stat = r.u8()?;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.ly = r.u8()?;
+        self.lyc = r.u8()?;
+        self.bgp = r.u8()?;
+        self.obp0 = r.u8()?;
+        self.obp1 = r.u8()?;
+        self.wy = r.u8()?;
+        self.wx = r.u8()?;
+        self.mode = PpuMode::from_bits(r.u8()?);
+        self.cycle_accumulator = r.u32()?;
+        self.stat_line = r.bool()?;
+        self.window_line = r.u8()?;
+        self.window_y_triggered = r.bool()?;
+        self.window_wrap = r.bool()?;
+        self.fifo.load_state(r)
+    }
+
     pub fn is_lcd_enabled(&self) -> bool {
         (self.lcdc & 0x80) != 0
     }
@@ -0,0 +1,439 @@
+// Dot-accurate renderer: background/window fetcher feeding a pixel FIFO, with
+// sprite fetches stalling it, as described in
+// https://gbdev.io/pandocs/pixel_fifo.html
+// Mode 3 lasts 172 dots plus SCX fine scroll, window and sprite penalties, and
+// registers are sampled at the dot they're used.
+use std::collections::VecDeque;
+
+use super::{Ppu, PpuMode};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// The BG FIFO never holds more than two tiles' worth of pixels
+const BG_FIFO_SIZE: usize = 16;
+const OBJ_FIFO_SIZE: usize = 8;
+// Each sprite fetch stalls the pixel pipeline for this many dots
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+impl FetcherStep {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => FetcherStep::Tile,
+            1 => FetcherStep::DataLow,
+            2 => FetcherStep::DataHigh,
+            _ => FetcherStep::Push,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ObjPixel {
+    color: u8,
+    obp1: bool,
+    behind_bg: bool,
+}
+
+pub(super) struct PixelFifo {
+    bg: VecDeque<u8>,
+    obj: VecDeque<ObjPixel>,
+
+    step: FetcherStep,
+    // Each of the Tile/DataLow/DataHigh steps takes two dots
+    step_dots: u8,
+    fetcher_x: u8,
+    tile_index: u8,
+    data_low: u8,
+    data_high: u8,
+    // The first fetch of every line is thrown away
+    dummy_fetch: bool,
+
+    // Pixels sent to the LCD so far on this line
+    lx: u8,
+    // Pixels still to drop from the front of the BG FIFO (SCX fine scroll, WX < 7)
+    discard: u8,
+    window_mode: bool,
+    window_drawn: bool,
+
+    // Sprites selected by OAM scan that haven't been fetched yet, in OAM order
+    sprites: Vec<usize>,
+    // Dots left on the sprite fetch in progress, and which sprite it is
+    sprite_fetch: Option<(usize, u8)>,
+}
+
+impl PixelFifo {
+    pub(super) fn new() -> Self {
+        PixelFifo {
+            bg: VecDeque::with_capacity(BG_FIFO_SIZE),
+            obj: VecDeque::with_capacity(OBJ_FIFO_SIZE),
+            step: FetcherStep::Tile,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile_index: 0,
+            data_low: 0,
+            data_high: 0,
+            dummy_fetch: true,
+            lx: 0,
+            discard: 0,
+            window_mode: false,
+            window_drawn: false,
+            sprites: Vec::new(),
+            sprite_fetch: None,
+        }
+    }
+
+    // Fixed-size layout regardless of FIFO contents, so every state for a ROM
+    // has the same length
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bg.len() as u8);
+        for i in 0..BG_FIFO_SIZE {
+            w.u8(self.bg.get(i).copied().unwrap_or(0));
+        }
+        w.u8(self.obj.len() as u8);
+        for i in 0..OBJ_FIFO_SIZE {
+            let pixel = self.obj.get(i).copied().unwrap_or_default();
+            w.u8(pixel.color);
+            w.bool(pixel.obp1);
+            w.bool(pixel.behind_bg);
+        }
+        w.u8(self.step as u8);
+        w.u8(self.step_dots);
+        w.u8(self.fetcher_x);
+        w.u8(self.tile_index);
+        w.u8(self.data_low);
+        w.u8(self.data_high);
+        w.bool(self.dummy_fetch);
+        w.u8(self.lx);
+        w.u8(self.discard);
+        w.bool(self.window_mode);
+        w.bool(self.window_drawn);
+        // Up to 40 pending sprites; 0xFF marks an empty slot
+        for i in 0..40 {
+            w.u8(self.sprites.get(i).map_or(0xFF, |&s| s as u8));
+        }
+        let (sprite, dots) = self.sprite_fetch.unwrap_or((0xFF, 0));
+        w.u8(sprite as u8);
+        w.u8(dots);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let bg_len = (r.u8()? as usize).min(BG_FIFO_SIZE);
+        self.bg.clear();
+        for i in 0..BG_FIFO_SIZE {
+            let color = r.u8()? & 0x03;
+            if i < bg_len {
+                self.bg.push_back(color);
+            }
+        }
+        let obj_len = (r.u8()? as usize).min(OBJ_FIFO_SIZE);
+        self.obj.clear();
+        for i in 0..OBJ_FIFO_SIZE {
+            let pixel = ObjPixel {
+                color: r.u8()? & 0x03,
+                obp1: r.bool()?,
+                behind_bg: r.bool()?,
+            };
+            if i < obj_len {
+                self.obj.push_back(pixel);
+            }
+        }
+        self.step = FetcherStep::from_bits(r.u8()?);
+        self.step_dots = r.u8()?;
+        self.fetcher_x = r.u8()?;
+        self.tile_index = r.u8()?;
+        self.data_low = r.u8()?;
+        self.data_high = r.u8()?;
+        self.dummy_fetch = r.bool()?;
+        self.lx = r.u8()?.min(160);
+        self.discard = r.u8()?;
+        self.window_mode = r.bool()?;
+        self.window_drawn = r.bool()?;
+        self.sprites.clear();
+        for _ in 0..40 {
+            let sprite = r.u8()?;
+            if sprite < 40 {
+                self.sprites.push(sprite as usize);
+            }
+        }
+        let sprite = r.u8()?;
+        let dots = r.u8()?;
+        self.sprite_fetch = (sprite < 40).then_some((sprite as usize, dots));
+        Ok(())
+    }
+}
+
+impl Ppu {
+    // Returns (VBlank Interrupt, Stat Interrupt)
+    pub(super) fn tick_fifo(&mut self, cycles: u8) -> (bool, bool) {
+        let mut vblank_irq = false;
+        let mut stat_irq = false;
+        for _ in 0..cycles {
+            vblank_irq |= self.tick_dot();
+            stat_irq |= self.update_stat_line();
+        }
+        (vblank_irq, stat_irq)
+    }
+
+    // Advances one dot; returns true when VBlank starts
+    fn tick_dot(&mut self) -> bool {
+        let mut vblank_irq = false;
+        let dot = self.cycle_accumulator;
+
+        if self.ly < 144 {
+            if dot == 0 {
+                self.mode = PpuMode::OamSearch;
+                if self.ly == self.wy {
+                    self.window_y_triggered = true;
+                }
+            } else if dot == 80 {
+                self.mode = PpuMode::PixelTransfer;
+                self.start_pixel_transfer();
+            }
+            if self.mode == PpuMode::PixelTransfer {
+                self.step_pixel_transfer();
+            }
+        }
+
+        self.cycle_accumulator += 1;
+        if self.cycle_accumulator >= 456 {
+            self.cycle_accumulator = 0;
+            self.ly += 1;
+            if self.ly == 144 {
+                self.mode = PpuMode::VBlank;
+                vblank_irq = true;
+            } else if self.ly >= 154 {
+                self.ly = 0;
+                self.mode = PpuMode::OamSearch;
+                self.reset_window();
+            }
+        }
+        vblank_irq
+    }
+
+    fn start_pixel_transfer(&mut self) {
+        // A WX = 166 line only carries the window into this one if it's still
+        // showing as the line starts, matching the scanline renderer
+        if !self.bg_window_enabled() || !self.is_window_enabled() || !self.window_y_triggered {
+            self.window_wrap = false;
+        }
+        let mut fifo = PixelFifo::new();
+        fifo.sprites = self.scan_oam();
+        // The first SCX % 8 pixels of the line are fetched and then dropped
+        fifo.discard = self.scx & 0x07;
+        self.fifo = fifo;
+    }
+
+    fn step_pixel_transfer(&mut self) {
+        self.check_window_trigger();
+
+        if let Some((sprite, dots)) = self.fifo.sprite_fetch {
+            if dots > 1 {
+                self.fifo.sprite_fetch = Some((sprite, dots - 1));
+            } else {
+                self.fifo.sprite_fetch = None;
+                self.merge_sprite(sprite);
+            }
+            return;
+        }
+
+        // A sprite starting at the current pixel stops output until it's been
+        // fetched, which can only begin once the BG fetcher has pixels queued.
+        // When several are due, the lowest X (then lowest OAM index) goes first
+        // so it claims the OBJ FIFO slots, like draw_sprites' priority order.
+        let sprite_due = if self.obj_enabled() {
+            let lx = self.fifo.lx as u16;
+            self.fifo
+                .sprites
+                .iter()
+                .enumerate()
+                .filter(|&(_, &i)| self.oam[i * 4 + 1] as u16 <= lx + 8)
+                .min_by_key(|&(_, &i)| (self.oam[i * 4 + 1], i))
+                .map(|(pos, _)| pos)
+        } else {
+            None
+        };
+        if let Some(pos) = sprite_due {
+            if self.fifo.bg.is_empty() || self.fifo.step != FetcherStep::Push {
+                self.step_fetcher();
+            } else {
+                let sprite = self.fifo.sprites.remove(pos);
+                self.fifo.sprite_fetch = Some((sprite, SPRITE_FETCH_DOTS));
+            }
+            return;
+        }
+
+        self.output_pixel();
+        self.step_fetcher();
+
+        if self.fifo.lx == 160 {
+            self.mode = PpuMode::HBlank;
+            if self.fifo.window_drawn {
+                self.window_line += 1;
+            }
+        }
+    }
+
+    // Switches the fetcher over to the window once the LCD reaches WX - 7
+    fn check_window_trigger(&mut self) {
+        if self.fifo.window_mode
+            || !self.bg_window_enabled()
+            || !self.is_window_enabled()
+            || !self.window_y_triggered
+        {
+            return;
+        }
+        let lx = self.fifo.lx;
+        let triggered = if self.window_wrap {
+            true
+        } else {
+            self.wx <= 166 && lx as u16 + 7 >= self.wx as u16
+        };
+        if !triggered {
+            return;
+        }
+
+        // WX < 7 starts the window partly off screen; WX = 166 makes it fill
+        // the next line as well
+        self.fifo.discard = if self.window_wrap || self.wx >= 7 {
+            0
+        } else {
+            7 - self.wx
+        };
+        self.window_wrap = self.wx == 166;
+        self.fifo.window_mode = true;
+        self.fifo.window_drawn = true;
+        self.fifo.bg.clear();
+        self.fifo.step = FetcherStep::Tile;
+        self.fifo.step_dots = 0;
+        self.fifo.fetcher_x = 0;
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fifo.step == FetcherStep::Push {
+            self.push_bg_tile();
+            return;
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < 2 {
+            return;
+        }
+        self.fifo.step_dots = 0;
+
+        match self.fifo.step {
+            FetcherStep::Tile => {
+                let address = if self.fifo.window_mode {
+                    let row = (self.window_line / 8) as u16;
+                    self.window_tile_map_area() + row * 32 + (self.fifo.fetcher_x & 0x1F) as u16
+                } else {
+                    let row = (self.scy.wrapping_add(self.ly) / 8) as u16;
+                    let col = ((self.scx / 8).wrapping_add(self.fifo.fetcher_x) & 0x1F) as u16;
+                    self.bg_tile_map_area() + row * 32 + col
+                };
+                self.fifo.tile_index = self.vram_read(address);
+                self.fifo.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fifo.data_low = self.vram_read(self.bg_row_address());
+                self.fifo.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fifo.data_high = self.vram_read(self.bg_row_address() + 1);
+                self.fifo.step = FetcherStep::Push;
+                // The push can happen on the same dot the data arrives
+                self.push_bg_tile();
+            }
+            FetcherStep::Push => unreachable!(),
+        }
+    }
+
+    fn bg_row_address(&self) -> u16 {
+        let row = if self.fifo.window_mode {
+            self.window_line % 8
+        } else {
+            self.scy.wrapping_add(self.ly) % 8
+        };
+        self.tile_data_address(self.fifo.tile_index) + row as u16 * 2
+    }
+
+    // Pushes the fetched tile row once the FIFO has drained
+    fn push_bg_tile(&mut self) {
+        if self.fifo.dummy_fetch {
+            self.fifo.dummy_fetch = false;
+            self.fifo.step = FetcherStep::Tile;
+            return;
+        }
+        if !self.fifo.bg.is_empty() {
+            return;
+        }
+        for bit in (0..8).rev() {
+            let low = (self.fifo.data_low >> bit) & 1;
+            let high = (self.fifo.data_high >> bit) & 1;
+            self.fifo.bg.push_back((high << 1) | low);
+        }
+        self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+        self.fifo.step = FetcherStep::Tile;
+    }
+
+    // Mixes a fetched sprite into the OBJ FIFO. Pixels already there belong to
+    // higher-priority sprites, so only transparent slots are filled.
+    fn merge_sprite(&mut self, sprite: usize) {
+        let flags = self.oam[sprite * 4 + 3];
+        // Sprites hanging off the left edge lose their first 8 - X pixels
+        let skip = (self.fifo.lx as usize + 8).saturating_sub(self.oam[sprite * 4 + 1] as usize);
+        while self.fifo.obj.len() < OBJ_FIFO_SIZE {
+            self.fifo.obj.push_back(ObjPixel::default());
+        }
+        for (i, color) in self
+            .sprite_pixels(sprite)
+            .into_iter()
+            .skip(skip)
+            .enumerate()
+        {
+            let slot = &mut self.fifo.obj[i];
+            if slot.color == 0 {
+                *slot = ObjPixel {
+                    color,
+                    obp1: (flags & 0x10) != 0,
+                    behind_bg: (flags & 0x80) != 0,
+                };
+            }
+        }
+    }
+
+    fn output_pixel(&mut self) {
+        let Some(bg_color) = self.fifo.bg.pop_front() else {
+            return;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let obj = self.fifo.obj.pop_front().unwrap_or_default();
+
+        // With LCDC.0 clear the BG and window are blank (color 0)
+        let bg_color = if self.bg_window_enabled() {
+            bg_color
+        } else {
+            0
+        };
+        let obj_visible = obj.color != 0 && self.obj_enabled() && !(obj.behind_bg && bg_color != 0);
+        let color = if obj_visible {
+            let palette = if obj.obp1 { self.obp1 } else { self.obp0 };
+            self.get_color(obj.color, palette)
+        } else {
+            self.get_color(bg_color, self.bgp)
+        };
+
+        let x = self.fifo.lx as usize;
+        self.bg_line[x] = bg_color;
+        self.buffer[self.ly as usize * 160 + x] = color;
+        self.fifo.lx += 1;
+    }
+}
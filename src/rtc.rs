@@ -0,0 +1,293 @@
+// https://gbdev.io/pandocs/MBC3.html#the-clock-counter-registers
+// The clock is advanced lazily from host wall time whenever it is touched,
+// so it keeps running while the emulator is paused or closed. Headless runs
+// can switch it to emulated time instead, so the same ROM and frame count
+// always give the same result.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::gameboy::CPU_CLOCK_HZ;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// Size of the RTC trailer appended to .sav files (VBA-M / BGB / mGBA format)
+pub const RTC_SAVE_SIZE: usize = 48;
+// Older emulators wrote a 32-bit timestamp instead of a 64-bit one
+pub const RTC_SAVE_SIZE_LEGACY: usize = 44;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // 9 bits, bit 8 lives in DH bit 0
+    halted: bool,
+    day_carry: bool,
+}
+
+impl RtcRegisters {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => (self.days & 0xFF) as u8,
+            0x0C => self.day_high(),
+            _ => 0xFF,
+        }
+    }
+
+    fn day_high(&self) -> u8 {
+        let mut value = ((self.days >> 8) & 0x01) as u8;
+        if self.halted {
+            value |= 0x40;
+        }
+        if self.day_carry {
+            value |= 0x80;
+        }
+        value
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // Registers can be written with out of range values (e.g. 60 seconds);
+        // hardware counts them up until they overflow their bit width, then wraps to 0.
+        while seconds > 0 {
+            // Fast path for whole days once everything is in range
+            if self.seconds < 60 && self.minutes < 60 && self.hours < 24 && seconds >= 86_400 {
+                let whole_days = seconds / 86_400;
+                seconds %= 86_400;
+                self.add_days(whole_days);
+                continue;
+            }
+
+            seconds -= 1;
+            self.seconds = (self.seconds + 1) & 0x3F;
+            if self.seconds != 60 {
+                continue;
+            }
+            self.seconds = 0;
+            self.minutes = (self.minutes + 1) & 0x3F;
+            if self.minutes != 60 {
+                continue;
+            }
+            self.minutes = 0;
+            self.hours = (self.hours + 1) & 0x1F;
+            if self.hours != 24 {
+                continue;
+            }
+            self.hours = 0;
+            self.add_days(1);
+        }
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let total = self.days as u64 + days;
+        if total > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (total & 0x1FF) as u16;
+    }
+}
+
+#[derive(Debug)]
+pub struct Rtc {
+    live: RtcRegisters,
+    latched: RtcRegisters,
+    last_latch_write: u8,
+    // Time (in seconds, see `now`) the live registers were last brought up to date
+    last_update: u64,
+    // CPU cycles run since switching to emulated time; None follows the host clock
+    emulated_cycles: Option<u64>,
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc {
+            live: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            last_latch_write: 0xFF,
+            last_update: host_now(),
+            emulated_cycles: None,
+        }
+    }
+
+    // Stops following the host clock: from here on the clock only advances
+    // with `tick`, and time spent outside the emulator doesn't count
+    pub fn use_emulated_time(&mut self) {
+        self.emulated_cycles = Some(0);
+        self.last_update = 0;
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        if let Some(total) = &mut self.emulated_cycles {
+            *total += cycles as u64;
+        }
+    }
+
+    // Unix seconds, or seconds of emulated time
+    fn now(&self) -> u64 {
+        match self.emulated_cycles {
+            Some(cycles) => cycles / CPU_CLOCK_HZ as u64,
+            None => host_now(),
+        }
+    }
+
+    // Bring the live registers up to the current time
+    fn update(&mut self) {
+        let now = self.now();
+        if !self.live.halted && now > self.last_update {
+            self.live.advance(now - self.last_update);
+        }
+        self.last_update = now;
+    }
+
+    // Writing 0x00 then 0x01 to 6000-7FFF copies the live clock into the latched registers
+    pub fn write_latch(&mut self, value: u8) {
+        if self.last_latch_write == 0x00 && value == 0x01 {
+            self.update();
+            self.latched = self.live;
+        }
+        self.last_latch_write = value;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.read(reg)
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) {
+        self.update();
+        match reg {
+            0x08 => self.live.seconds = value & 0x3F,
+            0x09 => self.live.minutes = value & 0x3F,
+            0x0A => self.live.hours = value & 0x1F,
+            0x0B => self.live.days = (self.live.days & 0x100) | value as u16,
+            0x0C => {
+                self.live.days = (self.live.days & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.live.halted = (value & 0x40) != 0;
+                self.live.day_carry = (value & 0x80) != 0;
+            }
+            _ => {}
+        }
+        // Games expect to read back what they wrote without re-latching
+        self.latched = self.live;
+    }
+
+    // Serialises the clock as the 48-byte trailer: live registers, latched
+    // registers (each as five little-endian u32s) and a 64-bit unix timestamp.
+    pub fn save_bytes(&mut self) -> Vec<u8> {
+        self.update();
+        let mut out = Vec::with_capacity(RTC_SAVE_SIZE);
+        for regs in [self.live, self.latched] {
+            for reg in 0x08..=0x0C {
+                out.extend_from_slice(&(regs.read(reg) as u32).to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&self.last_update.to_le_bytes());
+        out
+    }
+
+    // Restores the clock from a save trailer. The time that passed since it was
+    // written is caught up on the next access, unless the clock runs on
+    // emulated time. Returns false if the trailer isn't recognised.
+    pub fn load_save_bytes(&mut self, data: &[u8]) -> bool {
+        let timestamp = match data.len() {
+            RTC_SAVE_SIZE => u64::from_le_bytes(data[40..48].try_into().unwrap()),
+            RTC_SAVE_SIZE_LEGACY => u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64,
+            _ => return false,
+        };
+
+        let word = |i: usize| data[i * 4];
+        let parse = |base: usize| {
+            let dh = word(base + 4);
+            RtcRegisters {
+                seconds: word(base) & 0x3F,
+                minutes: word(base + 1) & 0x3F,
+                hours: word(base + 2) & 0x1F,
+                days: word(base + 3) as u16 | (((dh & 0x01) as u16) << 8),
+                halted: (dh & 0x40) != 0,
+                day_carry: (dh & 0x80) != 0,
+            }
+        };
+
+        self.live = parse(0);
+        self.latched = parse(5);
+        self.last_update = match self.emulated_cycles {
+            Some(_) => self.now(),
+            None => timestamp,
+        };
+        true
+    }
+
+    // Same layout as the .sav trailer, so a restored state keeps counting from
+    // when it was taken
+    pub fn save_state(&mut self, w: &mut StateWriter) {
+        w.bytes(&self.save_bytes());
+        w.u8(self.last_latch_write);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        if !self.load_save_bytes(r.bytes()?) {
+            return Err(SaveStateError::Truncated);
+        }
+        self.last_latch_write = r.u8()?;
+        Ok(())
+    }
+}
+
+fn host_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    fn run_seconds(rtc: &mut Rtc, seconds: u32) {
+        for _ in 0..seconds * (CPU_CLOCK_HZ / 4) {
+            rtc.tick(4);
+        }
+    }
+
+    #[test]
+    fn emulated_time_follows_cycles() {
+        let mut rtc = Rtc::new();
+        rtc.use_emulated_time();
+        run_seconds(&mut rtc, 61);
+        latch(&mut rtc);
+        assert_eq!((rtc.read(0x08), rtc.read(0x09)), (1, 1));
+    }
+
+    #[test]
+    fn emulated_time_ignores_save_timestamp() {
+        let mut saved = Rtc::new();
+        saved.write(0x08, 30);
+        // Written a year before now, which a host clock would catch up on
+        let mut bytes = saved.save_bytes();
+        let old = saved.last_update - 365 * 86_400;
+        bytes[40..48].copy_from_slice(&old.to_le_bytes());
+
+        let mut rtc = Rtc::new();
+        rtc.load_save_bytes(&bytes);
+        rtc.use_emulated_time();
+        latch(&mut rtc);
+        assert_eq!((rtc.read(0x08), rtc.read(0x0B)), (30, 0));
+
+        let mut rtc = Rtc::new();
+        rtc.use_emulated_time();
+        rtc.load_save_bytes(&bytes);
+        latch(&mut rtc);
+        assert_eq!((rtc.read(0x08), rtc.read(0x0B)), (30, 0));
+    }
+}
@@ -0,0 +1,140 @@
+// Snapshot format for the whole machine.
+//
+// Layout: "GBSS" magic, format version (u32 LE), CRC32 of the ROM image, then
+// every component in a fixed order (CPU, bus, PPU, timer, APU, serial, DMA,
+// joypad, cartridge). Each component writes its own fields through `StateWriter`.
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"GBSS";
+// Bump whenever the layout of any component changes
+pub const FORMAT_VERSION: u32 = 9;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    // The state was taken while running a different ROM
+    WrongRom,
+    // Shorter or longer than the current machine's state
+    Truncated,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a save state file"),
+            SaveStateError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "save state format version {} is not supported (expected {})",
+                    v, FORMAT_VERSION
+                )
+            }
+            SaveStateError::WrongRom => write!(f, "save state belongs to a different ROM"),
+            SaveStateError::Truncated => write!(f, "save state is corrupt or truncated"),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length-prefixed so variable-sized blocks (cartridge RAM) can be checked on load
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self.pos + len;
+        let slice = self
+            .data
+            .get(self.pos..end)
+            .ok_or(SaveStateError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // Fills `out` from a length-prefixed block, which must match its size exactly
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<(), SaveStateError> {
+        let data = self.bytes()?;
+        if data.len() != out.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        out.copy_from_slice(data);
+        Ok(())
+    }
+}
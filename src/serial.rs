@@ -0,0 +1,137 @@
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// With the internal clock (8192 Hz) each bit takes 512 CPU cycles
+const CYCLES_PER_BIT: u16 = 512;
+// Cap on captured output so a game polling the link port can't grow it forever
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+// Whatever sits on the other end of the link cable
+pub trait SerialSink {
+    // Called once per transfer with the byte being sent; returns the byte the
+    // other side sends back
+    fn exchange(&mut self, byte: u8) -> u8;
+}
+
+pub struct Serial {
+    data: u8,    // SB
+    control: u8, // SC
+    bit_timer: u16,
+    bits_left: u8,
+    incoming: u8,
+    // Every byte sent, for test ROMs that print their results over serial
+    output: Vec<u8>,
+    sink: Option<Box<dyn SerialSink>>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            bit_timer: 0,
+            bits_left: 0,
+            incoming: 0xFF,
+            output: Vec::new(),
+            sink: None,
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Option<Box<dyn SerialSink>>) {
+        self.sink = sink;
+    }
+
+    // Hands over the bytes sent since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // returns true if an interrupt needs to be requested
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        // Transfers on the external clock wait for a partner that never clocks them
+        if self.bits_left == 0 || (self.control & 0x01) == 0 {
+            return false;
+        }
+
+        let mut cycles = cycles as u16;
+        while cycles > 0 && self.bits_left > 0 {
+            if self.bit_timer > cycles {
+                self.bit_timer -= cycles;
+                return false;
+            }
+            cycles -= self.bit_timer;
+            self.bit_timer = CYCLES_PER_BIT;
+
+            // Shift out the top bit of SB while the partner's bit comes in at the bottom
+            let in_bit = (self.incoming >> (self.bits_left - 1)) & 0x01;
+            self.data = (self.data << 1) | in_bit;
+            self.bits_left -= 1;
+        }
+
+        if self.bits_left == 0 {
+            self.control &= 0x7F;
+            return true;
+        }
+        false
+    }
+
+    fn start_transfer(&mut self) {
+        if self.output.len() >= OUTPUT_LIMIT {
+            self.output.drain(..OUTPUT_LIMIT / 2);
+        }
+        self.output.push(self.data);
+        // A disconnected cable reads as all ones
+        self.incoming = match self.sink.as_mut() {
+            Some(sink) => sink.exchange(self.data),
+            None => 0xFF,
+        };
+        self.bits_left = 8;
+        self.bit_timer = CYCLES_PER_BIT;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.data,
+            0xFF02 => self.control | 0x7E,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.data = value,
+            0xFF02 => {
+                self.control = value & 0x81;
+                if (value & 0x80) != 0 {
+                    self.start_transfer();
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u16(self.bit_timer);
+        w.u8(self.bits_left);
+        w.u8(self.incoming);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.data = r.u8()?;
+        self.control = r.u8()? & 0x81;
+        self.bit_timer = r.u16()?;
+        self.bits_left = r.u8()?.min(8);
+        self.incoming = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+// Draws the emulation speed ("98%") into the top-left corner of a frame, for
+// when the window title is hidden (fullscreen, tiling window managers, ...).
+
+// 3x5 glyphs, one row per byte, most significant of the low 3 bits on the left
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+const PERCENT: [u8; 5] = [0b101, 0b001, 0b010, 0b100, 0b101];
+
+const BACKGROUND: u32 = 0xFF00_0000;
+const FOREGROUND: u32 = 0xFFFF_FFFF;
+
+pub fn draw_speed(buffer: &mut [u32], width: usize, speed: u32) {
+    let glyphs: Vec<[u8; 5]> = speed
+        .to_string()
+        .bytes()
+        .map(|digit| DIGITS[(digit - b'0') as usize])
+        .chain(std::iter::once(PERCENT))
+        .collect();
+
+    // One pixel of padding around the text and between glyphs
+    let box_width = glyphs.len() * 4 + 1;
+    for y in 0..7 {
+        buffer[y * width..y * width + box_width].fill(BACKGROUND);
+    }
+    for (i, glyph) in glyphs.iter().enumerate() {
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    buffer[(row + 1) * width + 1 + i * 4 + col] = FOREGROUND;
+                }
+            }
+        }
+    }
+}
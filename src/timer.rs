@@ -1,11 +1,42 @@
 // https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
 // https://github.com/Ashiepaws/GBEDG/blob/master/timers/index.md
 // https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// After TIMA overflows it reads 0 for one M-cycle, then TMA is loaded and the
+// interrupt requested on the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reload {
+    Idle,
+    // The M-cycle TIMA reads 0; writing TIMA now cancels the reload
+    Pending,
+    // The M-cycle TMA is loaded; TIMA writes are ignored and TMA writes go
+    // straight through to TIMA
+    Reloading,
+}
+
+impl Reload {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            1 => Reload::Pending,
+            2 => Reload::Reloading,
+            _ => Reload::Idle,
+        }
+    }
+}
+
 pub struct Timer {
     div: u16,
     tima: u8,
     tma: u8,
     tac: u8,
+    reload: Reload,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Timer {
@@ -15,42 +46,63 @@ impl Timer {
             tima: 0,
             tma: 0,
             tac: 0,
+            reload: Reload::Idle,
         }
     }
     // returns true if an interrupt needs to be requested
     pub fn tick(&mut self, cycles: u8) -> bool {
-        let old_div = self.div;
-        self.div = self.div.wrapping_add(cycles as u16);
-        let clock_select = self.tac & 0x03;
-        let timer_enabled = (self.tac & 0x04) != 0;
-        // read timer's obscure behavior on pandocs
+        let mut irq = false;
+        for _ in 0..cycles / 4 {
+            irq |= self.tick_m_cycle();
+        }
+        irq
+    }
+
+    fn tick_m_cycle(&mut self) -> bool {
+        let mut irq = false;
+        match self.reload {
+            Reload::Idle => {}
+            Reload::Pending => {
+                self.tima = self.tma;
+                self.reload = Reload::Reloading;
+                irq = true;
+            }
+            Reload::Reloading => self.reload = Reload::Idle,
+        }
 
-        let bit_pos: u8 = match clock_select {
+        let old_signal = self.signal();
+        self.div = self.div.wrapping_add(4);
+        self.check_falling_edge(old_signal);
+        irq
+    }
+
+    // read timer's obscure behavior on pandocs: TIMA counts falling edges of
+    // the selected DIV bit ANDed with the enable bit, so writes to DIV and TAC
+    // can bump it too
+    fn signal(&self) -> bool {
+        let bit_pos = match self.tac & 0x03 {
             0 => 9,
             1 => 3,
             2 => 5,
             3 => 7,
             _ => unreachable!(),
         };
-        let old_bit = (old_div >> bit_pos) & 1;
-        let new_bit = (self.div >> bit_pos) & 1;
+        let timer_enabled = (self.tac & 0x04) != 0;
+        timer_enabled && (self.div >> bit_pos) & 1 == 1
+    }
 
-        if timer_enabled && old_bit == 1 && new_bit == 0 {
-            return self.increment_tima();
+    fn check_falling_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.signal() {
+            self.increment_tima();
         }
-
-        false // No interrupt
     }
 
-    fn increment_tima(&mut self) -> bool {
+    fn increment_tima(&mut self) {
         let (result, overflow) = self.tima.overflowing_add(1);
         self.tima = result;
-
         if overflow {
-            self.tima = self.tma;
-            return true; // Request Interrupt!
+            self.reload = Reload::Pending;
         }
-        false
     }
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
@@ -63,12 +115,111 @@ impl Timer {
     }
 
     pub fn write(&mut self, addr: u16, value: u8) {
+        let old_signal = self.signal();
         match addr {
             0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
+            0xFF05 => match self.reload {
+                Reload::Reloading => {}
+                _ => {
+                    self.tima = value;
+                    self.reload = Reload::Idle;
+                }
+            },
+            0xFF06 => {
+                self.tma = value;
+                if self.reload == Reload::Reloading {
+                    self.tima = value;
+                }
+            }
             0xFF07 => self.tac = value,
             _ => {}
         }
+        self.check_falling_edge(old_signal);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.div);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u8(self.reload as u8);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.div = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.reload = Reload::from_bits(r.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TAC = 0x05: enabled, TIMA counts every 4 M-cycles (DIV bit 3)
+    fn timer_about_to_overflow() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x05);
+        timer.write(0xFF06, 0x42);
+        timer.write(0xFF05, 0xFF);
+        // Three M-cycles to the falling edge on the fourth
+        for _ in 0..3 {
+            assert!(!timer.tick(4));
+        }
+        timer
+    }
+
+    #[test]
+    fn overflow_reloads_one_m_cycle_late() {
+        let mut timer = timer_about_to_overflow();
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert!(timer.tick(4));
+        assert_eq!(timer.read(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn tima_write_before_reload_cancels_it() {
+        let mut timer = timer_about_to_overflow();
+        timer.tick(4);
+        timer.write(0xFF05, 0x10);
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read(0xFF05), 0x10);
+    }
+
+    #[test]
+    fn writes_while_reloading() {
+        let mut timer = timer_about_to_overflow();
+        timer.tick(4);
+        timer.tick(4);
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x42);
+        timer.write(0xFF06, 0x77);
+        assert_eq!(timer.read(0xFF05), 0x77);
+    }
+
+    #[test]
+    fn div_reset_on_high_bit_increments_tima() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x05);
+        timer.tick(8);
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+    }
+
+    #[test]
+    fn tac_change_on_high_bit_increments_tima() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x05);
+        timer.tick(8);
+        // Disabling the timer, or selecting a bit that's low, is a falling edge
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 1);
+        timer.write(0xFF07, 0x05);
+        timer.write(0xFF07, 0x06);
+        assert_eq!(timer.read(0xFF05), 2);
     }
 }
@@ -0,0 +1,120 @@
+// Draws the same screens with both renderers and checks the dot-by-dot FIFO
+// renderer agrees with the scanline one on sprite priority and window edge
+// cases. The ROMs are assembled in memory, so no test ROMs are needed.
+use gameboy_emulator::{Cartridge, GameBoy, Renderer};
+
+const TILES: u16 = 0x0200;
+const OAM: u16 = 0x0300;
+
+// Copies `len` bytes from ROM `src` to `dst`
+fn copy(code: &mut Vec<u8>, src: u16, dst: u16, len: u8) {
+    code.extend_from_slice(&[0x21, dst as u8, (dst >> 8) as u8]); // LD HL,dst
+    code.extend_from_slice(&[0x11, src as u8, (src >> 8) as u8]); // LD DE,src
+    code.extend_from_slice(&[0x06, len]); // LD B,len
+    code.extend_from_slice(&[0x1A, 0x22, 0x13, 0x05, 0x20, 0xFA]); // LD A,(DE); LD (HL+),A; INC DE; DEC B; JR NZ
+}
+
+// LDH (reg),value
+fn write_reg(code: &mut Vec<u8>, reg: u8, value: u8) {
+    code.extend_from_slice(&[0x3E, value, 0xE0, reg]);
+}
+
+// Spins until LY reads `line`
+fn wait_for_line(code: &mut Vec<u8>, line: u8) {
+    code.extend_from_slice(&[0xF0, 0x44, 0xFE, line, 0x20, 0xFA]);
+}
+
+// Loads the tiles and sprites with the LCD off, runs `setup`, turns the LCD
+// on with `lcdc` and then loops over `frame` forever
+fn build_rom(sprites: &[[u8; 4]], setup: &[u8], lcdc: u8, frame: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+
+    let mut code = Vec::new();
+    write_reg(&mut code, 0x40, 0x00); // LCD off
+    copy(&mut code, TILES, 0x8010, 48);
+    copy(&mut code, OAM, 0xFE00, 160);
+    write_reg(&mut code, 0x47, 0xE4); // BGP
+    write_reg(&mut code, 0x48, 0xE4); // OBP0
+    write_reg(&mut code, 0x49, 0x1B); // OBP1
+    code.extend_from_slice(setup);
+    write_reg(&mut code, 0x40, lcdc);
+    code.extend_from_slice(frame);
+    // JR back to the start of `frame`
+    code.extend_from_slice(&[0x18, (-(frame.len() as i8) - 2) as u8]);
+    rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+
+    // Tile 1 is solid color 1, tile 2 solid color 3, tile 3 stripes of 1 and 2
+    let tiles = [[0xFF, 0x00], [0xFF, 0xFF], [0xAA, 0x55]];
+    for (t, bytes) in tiles.iter().enumerate() {
+        for row in 0..8 {
+            let at = TILES as usize + t * 16 + row * 2;
+            rom[at..at + 2].copy_from_slice(bytes);
+        }
+    }
+
+    for (i, sprite) in sprites.iter().enumerate() {
+        let at = OAM as usize + i * 4;
+        rom[at..at + 4].copy_from_slice(sprite);
+    }
+    rom
+}
+
+fn render(rom: &[u8], renderer: Renderer) -> GameBoy {
+    let mut gb = GameBoy::new(Cartridge::from_bytes(rom.to_vec()).unwrap());
+    gb.set_renderer(renderer);
+    for _ in 0..3 {
+        gb.run_frame();
+    }
+    gb
+}
+
+fn assert_renderers_match(rom: &[u8]) {
+    let scanline = render(rom, Renderer::Scanline);
+    let fifo = render(rom, Renderer::Fifo);
+
+    let mut shades = scanline.framebuffer().to_vec();
+    shades.sort_unstable();
+    shades.dedup();
+    assert!(shades.len() > 1, "nothing was drawn");
+
+    assert_eq!(scanline.framebuffer_hash(), fifo.framebuffer_hash());
+}
+
+#[test]
+fn fifo_sprite_priority_matches_scanline() {
+    // Y, X, tile, flags
+    let sprites = [
+        [26, 20, 1, 0x00],
+        // Lower X than sprite 0 but a higher OAM index: still wins the overlap
+        [26, 16, 2, 0x00],
+        // Same X as sprite 0: the lower OAM index wins
+        [26, 20, 3, 0x00],
+        // Both hang off the left edge and are due at the same dot; X = 2 wins
+        [26, 6, 3, 0x00],
+        [26, 2, 2, 0x00],
+        [40, 40, 3, 0x20],
+        [40, 44, 1, 0x80],
+        [40, 36, 2, 0x10],
+    ];
+    assert_renderers_match(&build_rom(&sprites, &[], 0x83, &[]));
+}
+
+#[test]
+fn fifo_window_wrap_ends_when_window_is_disabled() {
+    // Fill the window map at 0x9C00 with the solid tile 2
+    let mut setup = vec![0x21, 0x00, 0x9C, 0x06, 0x02]; // LD HL,9C00; LD B,2
+    setup.extend_from_slice(&[0x78, 0x22, 0x7C, 0xFE, 0xA0, 0x20, 0xF9]); // LD A,B; LD (HL+),A; LD A,H; CP A0; JR NZ
+    write_reg(&mut setup, 0x4A, 20); // WY
+    write_reg(&mut setup, 0x4B, 166); // WX
+
+    // Line 20 shows one window pixel at WX = 166, which carries the window
+    // into line 21 only if it's still enabled. It's off for lines 21-22 and
+    // back on from 23, where WX = 166 shows a single pixel again.
+    let mut frame = Vec::new();
+    wait_for_line(&mut frame, 21);
+    write_reg(&mut frame, 0x40, 0xD3); // window off
+    wait_for_line(&mut frame, 23);
+    write_reg(&mut frame, 0x40, 0xF3); // window on, map 0x9C00, tiles at 0x8000
+    assert_renderers_match(&build_rom(&[], &setup, 0xF3, &frame));
+}